@@ -0,0 +1,405 @@
+/// Rebindable actions
+///
+/// Input systems ask whether an `Action` is active instead of matching on specific keys; the
+/// `ActionMap` resource decides which buttons and controller axes trigger which action. Bindings
+/// are loaded from a RON file mapping each action to a list of bindings, e.g.:
+///
+/// ```text
+/// {
+///     MoveLeft: [Keyboard(Left), Keyboard(A), Axis(0, Negative)],
+///     Jump: [Keyboard(Space), Controller(0)],
+///     Hook: [Mouse(Right), Axis(5, Positive)],
+/// }
+/// ```
+///
+/// Actions missing from the file keep their default bindings.
+
+use std::collections::BTreeMap;
+use piston::input::{Button, Key, MouseButton, ControllerButton};
+use ron;
+
+use error::{Error, ResultExt};
+
+/// Axis positions closer to the center than this are treated as the stick being at rest
+pub const AXIS_DEAD_ZONE: f64 = 0.2;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Shift,
+    Hook,
+    Aim,
+    /// Analog aiming, e.g. with a controller stick
+    AimLeft,
+    AimRight,
+    AimUp,
+    AimDown,
+    /// Drag to select a region in the editor
+    Select,
+    /// Enable edge-panning
+    Pan,
+    ToggleCamera,
+    ResetWorld,
+    /// Save to the current save slot
+    QuickSave,
+    /// Replace the world with the contents of the current save slot
+    QuickLoad,
+    /// Make the given slot the one used by `QuickSave` and `QuickLoad`
+    SelectSaveSlot(u8),
+    /// Delete the selected terrain box or room, or else the one under the mouse
+    Delete,
+    /// Revert the last editor operation
+    Undo,
+    /// Re-apply the last undone editor operation
+    Redo,
+    /// Switch between the editor's drawing and selection tools
+    NextTool,
+    /// Halve or double the size of the editor grid
+    GridSmaller,
+    GridLarger,
+    /// Turn snapping to the editor grid on or off
+    ToggleSnapping,
+    /// Choose the next kind of object to place with the editor's place tool
+    NextPaletteItem,
+    /// Copy the room under the mouse, with everything in it
+    Copy,
+    /// Paste the copied room at the mouse
+    Paste,
+    /// Copy the room under the mouse, and paste it next to the original
+    Duplicate,
+    /// Switch the layer that new terrain boxes are drawn on
+    NextLayer,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+impl AxisDirection {
+    /// How far `position` is pushed in this direction, from 0.0 to 1.0, ignoring the dead zone
+    /// around the center.
+    pub fn amount(&self, position: f64) -> f64 {
+        let amount = match *self {
+            AxisDirection::Positive => position.max(0.0),
+            AxisDirection::Negative => (-position).max(0.0),
+        };
+
+        if amount < AXIS_DEAD_ZONE {
+            0.0
+        } else {
+            ((amount - AXIS_DEAD_ZONE) / (1.0 - AXIS_DEAD_ZONE)).min(1.0)
+        }
+    }
+}
+
+/// Something that can trigger an action. Button variants mirror piston's `Button`, except that
+/// controller buttons match on any controller.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Keyboard(Key),
+    Mouse(MouseButton),
+    /// A button index on any controller
+    Controller(u8),
+    /// A controller axis pushed in one direction
+    Axis(u8, AxisDirection),
+    /// A key pressed while a modifier key is held, e.g. `Chord(LCtrl, Z)`; while the chord is
+    /// held, the key does not trigger its own bindings
+    Chord(Key, Key),
+}
+
+impl Binding {
+    pub fn matches(&self, button: &Button) -> bool {
+        match (*self, *button) {
+            (Binding::Keyboard(key), Button::Keyboard(pressed_key)) => key == pressed_key,
+            (Binding::Mouse(mouse_button), Button::Mouse(pressed_mouse_button)) =>
+                mouse_button == pressed_mouse_button,
+            (Binding::Controller(index), Button::Controller(ControllerButton { button, .. })) =>
+                index == button,
+            _ => false,
+        }
+    }
+}
+
+impl From<Button> for Binding {
+    fn from(button: Button) -> Self {
+        match button {
+            Button::Keyboard(key) => Binding::Keyboard(key),
+            Button::Mouse(mouse_button) => Binding::Mouse(mouse_button),
+            Button::Controller(controller_button) => Binding::Controller(controller_button.button),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ActionMap {
+    bindings: BTreeMap<Action, Vec<Binding>>,
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        let mut action_map = ActionMap { bindings: BTreeMap::new() };
+
+        // Controller indices follow the SDL game controller layout: button 0 is A, axes 0 and 1
+        // are the left stick, 2 and 3 the right stick, and 4 and 5 the triggers.
+        let default_bindings = [
+            (Action::MoveLeft, Binding::Keyboard(Key::Left)),
+            (Action::MoveLeft, Binding::Keyboard(Key::A)),
+            (Action::MoveLeft, Binding::Axis(0, AxisDirection::Negative)),
+            (Action::MoveRight, Binding::Keyboard(Key::Right)),
+            (Action::MoveRight, Binding::Keyboard(Key::D)),
+            (Action::MoveRight, Binding::Axis(0, AxisDirection::Positive)),
+            (Action::Jump, Binding::Keyboard(Key::Space)),
+            (Action::Jump, Binding::Controller(0)),
+            (Action::Shift, Binding::Keyboard(Key::Z)),
+            (Action::Shift, Binding::Axis(4, AxisDirection::Positive)),
+            (Action::Hook, Binding::Mouse(MouseButton::Right)),
+            (Action::Hook, Binding::Axis(5, AxisDirection::Positive)),
+            (Action::Aim, Binding::Keyboard(Key::LCtrl)),
+            (Action::AimLeft, Binding::Axis(2, AxisDirection::Negative)),
+            (Action::AimRight, Binding::Axis(2, AxisDirection::Positive)),
+            (Action::AimUp, Binding::Axis(3, AxisDirection::Negative)),
+            (Action::AimDown, Binding::Axis(3, AxisDirection::Positive)),
+            (Action::Select, Binding::Mouse(MouseButton::Left)),
+            (Action::Pan, Binding::Mouse(MouseButton::Middle)),
+            (Action::ToggleCamera, Binding::Keyboard(Key::C)),
+            (Action::ResetWorld, Binding::Keyboard(Key::R)),
+            (Action::QuickSave, Binding::Keyboard(Key::F5)),
+            (Action::QuickLoad, Binding::Keyboard(Key::F9)),
+            (Action::Delete, Binding::Keyboard(Key::Delete)),
+            (Action::Undo, Binding::Chord(Key::LCtrl, Key::Z)),
+            (Action::Undo, Binding::Chord(Key::RCtrl, Key::Z)),
+            (Action::Redo, Binding::Chord(Key::LCtrl, Key::Y)),
+            (Action::Redo, Binding::Chord(Key::RCtrl, Key::Y)),
+            (Action::Copy, Binding::Chord(Key::LCtrl, Key::C)),
+            (Action::Copy, Binding::Chord(Key::RCtrl, Key::C)),
+            (Action::Paste, Binding::Chord(Key::LCtrl, Key::V)),
+            (Action::Paste, Binding::Chord(Key::RCtrl, Key::V)),
+            (Action::Duplicate, Binding::Chord(Key::LCtrl, Key::D)),
+            (Action::Duplicate, Binding::Chord(Key::RCtrl, Key::D)),
+            (Action::NextTool, Binding::Keyboard(Key::Tab)),
+            (Action::GridSmaller, Binding::Keyboard(Key::LeftBracket)),
+            (Action::GridLarger, Binding::Keyboard(Key::RightBracket)),
+            (Action::ToggleSnapping, Binding::Keyboard(Key::G)),
+            (Action::NextPaletteItem, Binding::Keyboard(Key::E)),
+            (Action::NextLayer, Binding::Keyboard(Key::L)),
+        ];
+
+        for &(action, binding) in default_bindings.iter() {
+            action_map.bind(action, binding);
+        }
+
+        let slot_keys = [Key::D1, Key::D2, Key::D3, Key::D4, Key::D5, Key::D6, Key::D7, Key::D8, Key::D9];
+
+        for (slot, &key) in (1..).zip(slot_keys.iter()) {
+            action_map.bind(Action::SelectSaveSlot(slot), Binding::Keyboard(key));
+        }
+
+        action_map
+    }
+}
+
+impl ActionMap {
+    /// Load bindings from a RON file; if the file does not exist, the default bindings are used.
+    pub fn load(file_name: &str) -> Result<Self, Error> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut action_map = ActionMap::default();
+
+        let mut file = match File::open(file_name) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ::std::io::ErrorKind::NotFound => return Ok(action_map),
+            Err(error) => return Err(Error::from(error)
+                .context(format!("Cannot open bindings file '{}'", file_name)).into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .context(format!("Cannot read bindings file '{}'", file_name))?;
+
+        action_map.extend_from_ron_str(&contents)
+            .context(format!("Cannot deserialize bindings file '{}'", file_name))?;
+
+        Ok(action_map)
+    }
+
+    /// Replace the bindings of every action listed in `contents`, in the format of a bindings file.
+    fn extend_from_ron_str(&mut self, contents: &str) -> Result<(), ron::de::Error> {
+        let bindings: BTreeMap<Action, Vec<Binding>> = ron::de::from_str(contents)?;
+
+        self.bindings.extend(bindings);
+
+        Ok(())
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), Error> {
+        use std::fs::File;
+        use std::io::Write;
+
+        let mut serializer = ron::ser::Serializer::new(Some(Default::default()), true);
+        ::serde::Serialize::serialize(&self.bindings, &mut serializer)
+            .context("Cannot serialize bindings")?;
+
+        let mut file = File::create(file_name)
+            .context(format!("Cannot create bindings file '{}'", file_name))?;
+        file.write_all(serializer.into_output_string().as_bytes())
+            .context(format!("Cannot write bindings file '{}'", file_name))?;
+
+        Ok(())
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map(|bindings| bindings.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn is_bound(&self, action: Action, button: &Button) -> bool {
+        self.bindings(action).iter().any(|binding| binding.matches(button))
+    }
+
+    /// Whether any action is bound to pressing `key` while `modifier` is held.
+    pub fn has_chord(&self, modifier: Key, key: Key) -> bool {
+        self.bindings.values()
+            .flat_map(|bindings| bindings.iter())
+            .any(|binding| *binding == Binding::Chord(modifier, key))
+    }
+
+    /// The key among `held` that turns a press of `key` into a bound chord, if any.
+    pub fn chord_modifier<'b, I>(&self, held: I, key: Key) -> Option<Key>
+        where I: IntoIterator<Item = &'b Button>
+    {
+        held.into_iter()
+            .filter_map(|button| match *button {
+                Button::Keyboard(modifier) => Some(modifier),
+                _ => None,
+            })
+            .find(|&modifier| self.has_chord(modifier, key))
+    }
+
+    /// Add another binding that triggers `action`.
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.bindings.entry(action).or_insert_with(Vec::new);
+
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn unbind(&mut self, action: Action, binding: &Binding) {
+        if let Some(bindings) = self.bindings.get_mut(&action) {
+            bindings.retain(|bound| bound != binding);
+        }
+    }
+
+    /// Make `binding` the only binding that triggers `action`.
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        self.bindings.insert(action, vec![binding]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use piston::input::{Button, Key, MouseButton, ControllerButton};
+    use super::{Action, ActionMap, AxisDirection, Binding};
+
+    fn load(contents: &str) -> ActionMap {
+        let mut action_map = ActionMap::default();
+        action_map.extend_from_ron_str(contents).expect("Valid bindings");
+        action_map
+    }
+
+    #[test]
+    fn bindings_files_replace_the_bindings_of_the_actions_they_list() {
+        let action_map = load("{
+            MoveLeft: [Keyboard(J), Axis(0, Negative)],
+            Jump: [Mouse(Left), Controller(1)],
+            Undo: [Chord(LAlt, U)],
+        }");
+
+        assert_eq!(action_map.bindings(Action::MoveLeft),
+            &[Binding::Keyboard(Key::J), Binding::Axis(0, AxisDirection::Negative)]);
+        assert_eq!(action_map.bindings(Action::Jump),
+            &[Binding::Mouse(MouseButton::Left), Binding::Controller(1)]);
+        assert_eq!(action_map.bindings(Action::Undo), &[Binding::Chord(Key::LAlt, Key::U)]);
+        assert_eq!(action_map.bindings(Action::Hook), ActionMap::default().bindings(Action::Hook));
+    }
+
+    #[test]
+    fn malformed_bindings_files_are_rejected() {
+        let mut action_map = ActionMap::default();
+
+        assert!(action_map.extend_from_ron_str("{ Jump: [Keyboard(NotAKey)] }").is_err());
+        assert!(action_map.extend_from_ron_str("{ Fly: [Keyboard(Space)] }").is_err());
+        assert_eq!(action_map, ActionMap::default());
+    }
+
+    #[test]
+    fn saved_bindings_load_back_unchanged() {
+        let mut action_map = ActionMap::default();
+        action_map.rebind(Action::Shift, Binding::Keyboard(Key::S));
+
+        let mut serializer = ::ron::ser::Serializer::new(Some(Default::default()), true);
+        ::serde::Serialize::serialize(&action_map.bindings, &mut serializer).unwrap();
+
+        assert_eq!(load(&serializer.into_output_string()), action_map);
+    }
+
+    #[test]
+    fn buttons_match_bindings_of_the_same_kind() {
+        let controller_button = |id, button| Button::Controller(ControllerButton { id, button });
+
+        assert!(Binding::Keyboard(Key::A).matches(&Button::Keyboard(Key::A)));
+        assert!(!Binding::Keyboard(Key::A).matches(&Button::Keyboard(Key::B)));
+        assert!(Binding::Mouse(MouseButton::Left).matches(&Button::Mouse(MouseButton::Left)));
+        assert!(!Binding::Mouse(MouseButton::Left).matches(&Button::Mouse(MouseButton::Right)));
+        assert!(Binding::Controller(3).matches(&controller_button(0, 3)));
+        assert!(Binding::Controller(3).matches(&controller_button(1, 3)));
+        assert!(!Binding::Controller(3).matches(&controller_button(0, 4)));
+        assert!(!Binding::Axis(3, AxisDirection::Positive).matches(&controller_button(0, 3)));
+    }
+
+    #[test]
+    fn chords_need_their_modifier() {
+        let action_map = ActionMap::default();
+        let held = [Button::Keyboard(Key::LCtrl), Button::Mouse(MouseButton::Left)];
+
+        assert_eq!(action_map.chord_modifier(&held, Key::Z), Some(Key::LCtrl));
+        assert_eq!(action_map.chord_modifier(&held, Key::X), None);
+        assert_eq!(action_map.chord_modifier(&[Button::Keyboard(Key::LShift)], Key::Z), None);
+        assert_eq!(action_map.chord_modifier(&[], Key::Z), None);
+    }
+
+    #[test]
+    fn chords_do_not_match_their_key_alone() {
+        let action_map = ActionMap::default();
+
+        assert!(!action_map.is_bound(Action::Undo, &Button::Keyboard(Key::Z)));
+        assert!(action_map.is_bound(Action::Shift, &Button::Keyboard(Key::Z)));
+        assert!(!Binding::Chord(Key::LCtrl, Key::Z).matches(&Button::Keyboard(Key::Z)));
+        assert!(!Binding::Chord(Key::LCtrl, Key::Z).matches(&Button::Keyboard(Key::LCtrl)));
+    }
+
+    fn close_to(amount: f64, expected: f64) -> bool {
+        (amount - expected).abs() < 1e-9
+    }
+
+    #[test]
+    fn axis_positions_inside_the_dead_zone_count_as_at_rest() {
+        assert!(close_to(AxisDirection::Positive.amount(0.0), 0.0));
+        assert!(close_to(AxisDirection::Positive.amount(0.19), 0.0));
+        assert!(close_to(AxisDirection::Negative.amount(-0.19), 0.0));
+        assert!(close_to(AxisDirection::Negative.amount(0.8), 0.0));
+    }
+
+    #[test]
+    fn axis_positions_outside_the_dead_zone_are_rescaled() {
+        assert!(close_to(AxisDirection::Positive.amount(0.2), 0.0));
+        assert!(close_to(AxisDirection::Positive.amount(0.6), 0.5));
+        assert!(close_to(AxisDirection::Negative.amount(-0.6), 0.5));
+        assert!(close_to(AxisDirection::Positive.amount(1.0), 1.0));
+        assert!(close_to(AxisDirection::Positive.amount(1.5), 1.0));
+    }
+}
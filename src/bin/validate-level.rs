@@ -0,0 +1,82 @@
+extern crate stacked_worlds;
+
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Usage: validate-level FILE_OR_DIRECTORY...
+
+Checks levels and saves for problems, and exits with an error if any are found. Directories are
+searched recursively for .ron and .json files.";
+
+fn is_level(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("ron") | Some("json") => true,
+        _ => false,
+    }
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() || is_level(&entry) {
+            collect_files(&entry, files)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+
+    if paths.is_empty() || paths.iter().any(|path| path == "-h" || path == "--help") {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let mut files = Vec::new();
+
+    for path in &paths {
+        collect_files(Path::new(path), &mut files).unwrap_or_else(|err| {
+            eprintln!("Error: cannot read '{}': {}", path, err);
+            std::process::exit(2);
+        });
+    }
+
+    let mut failed = 0;
+
+    for file in &files {
+        let file_name = file.to_string_lossy();
+
+        match stacked_worlds::validate_level(&file_name) {
+            Ok(ref issues) if issues.is_empty() => println!("{}: ok", file_name),
+            Ok(issues) => {
+                failed += 1;
+                println!("{}: {} problem(s)", file_name, issues.len());
+
+                for issue in issues {
+                    println!("    {}", issue);
+                }
+            },
+            Err(err) => {
+                failed += 1;
+                println!("{}: {}", file_name, err);
+            },
+        }
+    }
+
+    println!("{} file(s) checked, {} with problems.", files.len(), failed);
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
@@ -1,14 +1,15 @@
-use specs::prelude::{System, VecStorage, DenseVecStorage, Entities, ReadExpect, ReadStorage, WriteStorage, Join, Builder};
-use specs::saveload::MarkedBuilder;
+use specs::prelude::{System, VecStorage, DenseVecStorage, Entity, Entities, ReadExpect, ReadStorage, WriteStorage, Join, Builder};
+use specs::saveload::{MarkedBuilder, Marker, IntoSerialize, FromDeserialize};
 use nalgebra::Vector2;
 
 use UpdateDeltaTime;
 use input::{PlayerController, Movement};
 use physics::{Velocity, Force, Aim, CollisionSet, InRoom, RevoluteJoint};
-use draw::{Position, Shape, ShapeClass};
+use draw::Position;
 use specs::LazyUpdate;
-use specs::world::Index;
-use saveload::DestroyEntity;
+use saveload::{DestroyEntity, optional_marker_of, optional_entity_of};
+use error::GameError;
+use prefab::Prefabs;
 use specs::saveload::U64Marker;
 
 #[derive(Component, Debug, Default, Serialize, Deserialize, Copy, Clone, PartialEq)]
@@ -76,14 +77,56 @@ impl <'a> System<'a> for ControlObjects {
     }
 }
 
-#[derive(Component, Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
 #[storage(DenseVecStorage)]
 pub struct ChainLink {
     // TODO: Maybe figure out how to move these to an Animation component
     pub creation_animation: f64,
     pub destruction_animation: f64,
     pub expire: bool,
-    pub next_link: Option<Index>,
+    pub next_link: Option<Entity>,
+}
+
+/// Saved form of `ChainLink`, with the next link referenced by its marker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "ChainLink")]
+pub struct ChainLinkData<M> {
+    pub creation_animation: f64,
+    pub destruction_animation: f64,
+    pub expire: bool,
+    pub next_link: Option<M>,
+}
+
+impl <M: Marker> IntoSerialize<M> for ChainLink {
+    type Data = ChainLinkData<M>;
+    type Error = GameError;
+
+    fn into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+        where F: FnMut(Entity) -> Option<M>
+    {
+        Ok(ChainLinkData {
+            creation_animation: self.creation_animation,
+            destruction_animation: self.destruction_animation,
+            expire: self.expire,
+            next_link: optional_marker_of("ChainLink", self.next_link, &mut ids)?,
+        })
+    }
+}
+
+impl <M: Marker> FromDeserialize<M> for ChainLink {
+    type Data = ChainLinkData<M>;
+    type Error = GameError;
+
+    fn from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+        where F: FnMut(M) -> Option<Entity>
+    {
+        Ok(ChainLink {
+            creation_animation: data.creation_animation,
+            destruction_animation: data.destruction_animation,
+            expire: data.expire,
+            next_link: optional_entity_of("ChainLink", data.next_link, &mut ids)?,
+        })
+    }
 }
 
 pub struct FireHook;
@@ -97,11 +140,12 @@ impl <'a> System<'a> for FireHook {
         ReadStorage<'a, Velocity>,
         ReadStorage<'a, InRoom>,
         ReadStorage<'a, Aim>,
+        ReadExpect<'a, Prefabs>,
         ReadExpect<'a, LazyUpdate>,
     );
 
     fn run(&mut self, (entities, mut player_controllers, mut chain_links,
-        positions, velocities, in_rooms, aims, lazy_update): Self::SystemData)
+        positions, velocities, in_rooms, aims, prefabs, lazy_update): Self::SystemData)
     {
         for (entity, mut player_controller, position, velocity, in_room, aim) in (&*entities, &mut player_controllers, &positions, &velocities, &in_rooms, &aims).join() {
             if player_controller.hooking && !player_controller.hook_established {
@@ -121,16 +165,16 @@ impl <'a> System<'a> for FireHook {
                     continue;
                 }
 
-                let mut linked_to_entity = target_entity.id();
+                let mut linked_to_entity = target_entity;
                 let mut next_link = None;
                 let mut creation_animation = 0.1;
 
                 for i in (2..=link_count as i32).rev() {
                     let chain_link_position = source + direction * 10.0 * (i as f64);
 
-                    let new_entity = lazy_update.create_entity(&entities)
+                    let new_entity = prefabs.spawn("chain_link", &entities, &lazy_update)
+                        .expect("Built-in prefabs always exist")
                         .with(Position { x: chain_link_position.x, y: chain_link_position.y })
-                        .with(Shape { size: 3.0, class: ShapeClass::ChainLink })
                         .with(Velocity { .. *velocity })
                         .with(InRoom { .. *in_room })
                         .with(ChainLink { next_link, creation_animation, .. ChainLink::default() })
@@ -138,7 +182,7 @@ impl <'a> System<'a> for FireHook {
                         .marked::<U64Marker>()
                         .build();
 
-                    linked_to_entity = new_entity.id();
+                    linked_to_entity = new_entity;
                     next_link = Some(linked_to_entity);
                     creation_animation += 0.02;
                 }
@@ -153,13 +197,15 @@ impl <'a> System<'a> for FireHook {
                 let mut some_next_entity = if let Some(chain_link) = chain_links.get(entity) {
                     chain_link.next_link
                 } else {
+                    // The chain is already gone, e.g. destroyed along with what it was attached to
+                    player_controller.hook_established = false;
                     continue
                 };
 
                 let mut destruction_animation = 0.5;
 
                 while let Some(next_entity) = some_next_entity {
-                    let mut chain_link = chain_links.get_mut(entities.entity(next_entity));
+                    let mut chain_link = chain_links.get_mut(next_entity);
 
                     some_next_entity = chain_link.and_then(|chain_link| {
                         chain_link.expire = true;
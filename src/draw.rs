@@ -1,10 +1,11 @@
 extern crate opengl_graphics;
 extern crate specs;
 
-use specs::prelude::{World, VecStorage, ReadStorage, ReadExpect, Join, System, Entities, RunNow};
+use specs::prelude::{World, Entity, VecStorage, ReadStorage, WriteStorage, ReadExpect, Join, System, Entities, RunNow};
 use specs::world::Index;
 use piston::input::RenderArgs;
 use graphics::Context;
+use graphics::math::Matrix2d;
 use opengl_graphics::GlGraphics;
 use animate::{Animation, RoomAnimation};
 use physics::InRoom;
@@ -14,11 +15,15 @@ use control::Jump;
 use physics::Aim;
 use control::ChainLink;
 use input::InputState;
-use physics::Room;
+use edit::{EditorController, EditorTool, Grid, HANDLE_SIZE, world_bounds, palette};
+use prefab::Prefabs;
+use physics::{Room, RoomProperties};
+use specs::saveload::{U64Marker, Marker};
 use specs::WriteExpect;
 use input::PlayerController;
 use UpdateDeltaTime;
-use shift::Shifter;
+use shift::{Shifter, RoomLink};
+use physics::Velocity;
 
 #[derive(Debug, Component, Serialize, Deserialize, Clone, Copy)]
 #[storage(VecStorage)]
@@ -27,6 +32,33 @@ pub struct Position {
     pub y: f64,
 }
 
+impl Position {
+    /// Blend between the position from the previous tick and this one; objects that have not
+    /// been through a tick yet are drawn where they are.
+    pub fn interpolate(&self, previous_position: Option<&PreviousPosition>, alpha: f64) -> Position {
+        match previous_position {
+            Some(previous) => Position {
+                x: previous.x + (self.x - previous.x) * alpha,
+                y: previous.y + (self.y - previous.y) * alpha,
+            },
+            None => *self,
+        }
+    }
+}
+
+/// The `Position` of a moving object as it was at the start of the current tick
+#[derive(Debug, Component, Clone, Copy)]
+#[storage(VecStorage)]
+pub struct PreviousPosition {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// How far rendering is between the last tick and the next one, from 0.0 to 1.0
+pub struct Interpolation {
+    pub alpha: f64,
+}
+
 #[derive(Component, Debug, Serialize, Deserialize, Clone, Copy)]
 #[storage(VecStorage)]
 pub struct Size {
@@ -34,6 +66,35 @@ pub struct Size {
     pub height: f64,
 }
 
+/// The layer of a terrain box. Terrain saved before layers existed has no `TerrainLayer`, and is on
+/// the collision layer.
+#[derive(Component, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[storage(VecStorage)]
+pub enum TerrainLayer {
+    /// Decoration drawn behind the room
+    Background,
+    /// Solid terrain that objects collide with
+    Collision,
+    /// Decoration drawn over the objects in the room
+    Foreground,
+}
+
+impl Default for TerrainLayer {
+    fn default() -> Self {
+        TerrainLayer::Collision
+    }
+}
+
+impl TerrainLayer {
+    pub fn next(&self) -> Self {
+        match *self {
+            TerrainLayer::Background => TerrainLayer::Collision,
+            TerrainLayer::Collision => TerrainLayer::Foreground,
+            TerrainLayer::Foreground => TerrainLayer::Background,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ShapeClass {
     Ball,
@@ -53,6 +114,12 @@ pub struct Camera {
     pub y: f64,
     pub zoom: f64,
 
+    /// Position at the start of the current tick; drawing blends from it to `x` and `y`
+    #[serde(default)]
+    pub previous_x: f64,
+    #[serde(default)]
+    pub previous_y: f64,
+
     pub target_x: f64,
     pub target_y: f64,
     pub target_zoom: f64,
@@ -97,6 +164,9 @@ impl Camera {
             y: 0.0,
             zoom: 1.0,
 
+            previous_x: 0.0,
+            previous_y: 0.0,
+
             target_x: 0.0,
             target_y: 0.0,
             target_zoom: 1.0,
@@ -109,6 +179,16 @@ impl Camera {
         }
     }
 
+    /// The camera as it is between the last tick and the next one, as `Position::interpolate`
+    /// does for objects.
+    pub fn interpolate(&self, alpha: f64) -> Camera {
+        Camera {
+            x: self.previous_x + (self.x - self.previous_x) * alpha,
+            y: self.previous_y + (self.y - self.previous_y) * alpha,
+            .. *self
+        }
+    }
+
     pub fn apply_stencil(self, gl: &mut GlGraphics, mut context: Context, phase_overlay: &PhaseOverlay, offset: bool) -> Context {
         use graphics::Transformed;
         use graphics::Graphics;
@@ -230,6 +310,24 @@ fn rectangle_to_lines(rect: [f64; 4]) -> [[f64; 4]; 4] {
     ]
 }
 
+pub struct StorePreviousPositions;
+
+impl <'a> System<'a> for StorePreviousPositions {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        WriteStorage<'a, PreviousPosition>,
+    );
+
+    fn run(&mut self, (entities, positions, velocities, mut previous_positions): Self::SystemData) {
+        for (entity, position, _velocity) in (&*entities, &positions, &velocities).join() {
+            previous_positions.insert(entity, PreviousPosition { x: position.x, y: position.y })
+                .expect("Could not insert PreviousPosition component");
+        }
+    }
+}
+
 pub struct ClearScreen<'a> {
     pub gl_graphics: &'a mut GlGraphics,
     pub render_args: RenderArgs,
@@ -253,9 +351,14 @@ pub struct DrawPhaseSphere<'a> {
 }
 
 impl <'a, 'b> System<'a> for DrawPhaseSphere<'b> {
-    type SystemData = ReadExpect<'a, Camera>;
+    type SystemData = (
+        ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
+    );
+
+    fn run(&mut self, (camera, interpolation): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
 
-    fn run(&mut self, camera: Self::SystemData) {
         if let Some(phase_overlay) = camera.phase_overlay {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::{Transformed, circle_arc};
@@ -275,6 +378,86 @@ impl <'a, 'b> System<'a> for DrawPhaseSphere<'b> {
     }
 }
 
+/// A dark color that stays the same for a room across saves, picked from `seed`
+fn seeded_color(seed: u64) -> [f32; 3] {
+    // Mix the bits, so that neighbouring seeds get unrelated colors
+    let mut x = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+
+    let channel = |shift: u32| 0.05 + 0.15 * ((x >> shift) & 0xFF) as f32 / 255.0;
+
+    [channel(0), channel(8), channel(16)]
+}
+
+/// Strokes of a character in a 4x6 cell, as lines from (x1, y1) to (x2, y2); there is no font
+/// rendering, so labels are drawn with lines like everything else. Lowercase letters are drawn
+/// as uppercase, and characters without strokes as a small box.
+fn glyph_strokes(character: char) -> &'static [[u8; 4]] {
+    match character.to_ascii_uppercase() {
+        'A' => &[[0, 6, 0, 2], [0, 2, 2, 0], [2, 0, 4, 2], [4, 2, 4, 6], [0, 3, 4, 3]],
+        'B' => &[[0, 0, 0, 6], [0, 0, 3, 0], [3, 0, 4, 1], [4, 1, 4, 2], [4, 2, 3, 3], [0, 3, 3, 3],
+                 [3, 3, 4, 4], [4, 4, 4, 5], [4, 5, 3, 6], [3, 6, 0, 6]],
+        'C' => &[[4, 0, 0, 0], [0, 0, 0, 6], [0, 6, 4, 6]],
+        'D' => &[[0, 0, 0, 6], [0, 0, 2, 0], [2, 0, 4, 2], [4, 2, 4, 4], [4, 4, 2, 6], [2, 6, 0, 6]],
+        'E' => &[[4, 0, 0, 0], [0, 0, 0, 6], [0, 6, 4, 6], [0, 3, 3, 3]],
+        'F' => &[[4, 0, 0, 0], [0, 0, 0, 6], [0, 3, 3, 3]],
+        'G' => &[[4, 0, 0, 0], [0, 0, 0, 6], [0, 6, 4, 6], [4, 6, 4, 3], [4, 3, 2, 3]],
+        'H' => &[[0, 0, 0, 6], [4, 0, 4, 6], [0, 3, 4, 3]],
+        'I' => &[[0, 0, 4, 0], [2, 0, 2, 6], [0, 6, 4, 6]],
+        'J' => &[[4, 0, 4, 6], [4, 6, 0, 6], [0, 6, 0, 4]],
+        'K' => &[[0, 0, 0, 6], [4, 0, 0, 3], [0, 3, 4, 6]],
+        'L' => &[[0, 0, 0, 6], [0, 6, 4, 6]],
+        'M' => &[[0, 6, 0, 0], [0, 0, 2, 3], [2, 3, 4, 0], [4, 0, 4, 6]],
+        'N' => &[[0, 6, 0, 0], [0, 0, 4, 6], [4, 6, 4, 0]],
+        'O' | '0' => &[[0, 0, 4, 0], [4, 0, 4, 6], [4, 6, 0, 6], [0, 6, 0, 0]],
+        'P' => &[[0, 6, 0, 0], [0, 0, 4, 0], [4, 0, 4, 3], [4, 3, 0, 3]],
+        'Q' => &[[0, 0, 4, 0], [4, 0, 4, 6], [4, 6, 0, 6], [0, 6, 0, 0], [2, 4, 4, 6]],
+        'R' => &[[0, 6, 0, 0], [0, 0, 4, 0], [4, 0, 4, 3], [4, 3, 0, 3], [0, 3, 4, 6]],
+        'S' | '5' => &[[4, 0, 0, 0], [0, 0, 0, 3], [0, 3, 4, 3], [4, 3, 4, 6], [4, 6, 0, 6]],
+        'T' => &[[0, 0, 4, 0], [2, 0, 2, 6]],
+        'U' => &[[0, 0, 0, 6], [0, 6, 4, 6], [4, 6, 4, 0]],
+        'V' => &[[0, 0, 2, 6], [2, 6, 4, 0]],
+        'W' => &[[0, 0, 1, 6], [1, 6, 2, 3], [2, 3, 3, 6], [3, 6, 4, 0]],
+        'X' => &[[0, 0, 4, 6], [4, 0, 0, 6]],
+        'Y' => &[[0, 0, 2, 3], [4, 0, 2, 3], [2, 3, 2, 6]],
+        'Z' => &[[0, 0, 4, 0], [4, 0, 0, 6], [0, 6, 4, 6]],
+        '1' => &[[1, 1, 2, 0], [2, 0, 2, 6], [0, 6, 4, 6]],
+        '2' => &[[0, 0, 4, 0], [4, 0, 4, 3], [4, 3, 0, 3], [0, 3, 0, 6], [0, 6, 4, 6]],
+        '3' => &[[0, 0, 4, 0], [4, 0, 4, 6], [4, 6, 0, 6], [1, 3, 4, 3]],
+        '4' => &[[0, 0, 0, 3], [0, 3, 4, 3], [4, 0, 4, 6]],
+        '6' => &[[4, 0, 0, 0], [0, 0, 0, 6], [0, 6, 4, 6], [4, 6, 4, 3], [4, 3, 0, 3]],
+        '7' => &[[0, 0, 4, 0], [4, 0, 1, 6]],
+        '8' => &[[0, 0, 4, 0], [4, 0, 4, 6], [4, 6, 0, 6], [0, 6, 0, 0], [0, 3, 4, 3]],
+        '9' => &[[4, 3, 0, 3], [0, 3, 0, 0], [0, 0, 4, 0], [4, 0, 4, 6], [4, 6, 0, 6]],
+        '-' => &[[1, 3, 3, 3]],
+        '_' => &[[0, 6, 4, 6]],
+        '.' => &[[2, 5, 2, 6]],
+        ' ' => &[],
+        _ => &[[1, 2, 3, 2], [3, 2, 3, 5], [3, 5, 1, 5], [1, 5, 1, 2]],
+    }
+}
+
+/// Draw `text` with its top-left corner at (x, y), in characters `scale` times the size of a
+/// glyph cell.
+fn draw_text(text: &str, x: f64, y: f64, scale: f64, color: [f32; 4], transform: Matrix2d, gl: &mut GlGraphics) {
+    use graphics::line;
+
+    for (index, character) in text.chars().enumerate() {
+        let left = x + index as f64 * 6.0 * scale;
+
+        for stroke in glyph_strokes(character) {
+            let [x1, y1, x2, y2] = *stroke;
+
+            line(color, 0.5, [
+                left + x1 as f64 * scale, y + y1 as f64 * scale,
+                left + x2 as f64 * scale, y + y2 as f64 * scale,
+            ], transform, gl);
+        }
+    }
+}
+
 pub struct DrawRooms<'a> {
     pub gl_graphics: &'a mut GlGraphics,
     pub render_args: RenderArgs,
@@ -287,13 +470,18 @@ impl <'a, 'b> System<'a> for DrawRooms<'b> {
         ReadStorage<'a, Size>,
         ReadStorage<'a, Animation<RoomAnimation>>,
         ReadStorage<'a, Room>,
-        ReadStorage<'a, InRoom>,
+        ReadStorage<'a, RoomProperties>,
+        ReadStorage<'a, U64Marker>,
         ReadExpect<'a, InputState>,
         ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
     );
 
-    fn run(&mut self, (entities, positions, sizes, animations, rooms, in_rooms, input_state, camera): Self::SystemData) {
-        // Draw room borders
+    fn run(&mut self, (entities, positions, sizes, animations, rooms, room_properties, markers,
+        input_state, camera, interpolation): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        // Draw room backgrounds and borders
         for (entity, position, size, animation, _room) in (&*entities, &positions, &sizes, &animations, &rooms).join() {
             if size.width < 5.0 || size.height < 5.0 {
                 continue;
@@ -310,24 +498,76 @@ impl <'a, 'b> System<'a> for DrawRooms<'b> {
                 brightness = brightness.max(0.4);
             }
 
+            // Markers survive saving and loading, entity ids do not
+            let background_color = room_properties.get(entity)
+                .and_then(|properties| properties.background_color)
+                .unwrap_or_else(|| seeded_color(markers.get(entity)
+                    .map_or(u64::from(entity.id()), |marker| marker.id())));
+
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
-                use graphics::line;
+                use graphics::{line, rectangle};
 
                 let (context, alpha) = camera.apply_transform(gl, context, Some(entity.id()));
 
-                let color = [brightness, brightness, brightness, alpha];
+                let [red, green, blue] = background_color;
+                rectangle([red, green, blue, alpha], room_rectangle, context.transform, gl);
 
-//                rectangle([0.2, 0.2, 0.5, 0.01], room_rectangle, context.transform, gl);
+                let color = [brightness, brightness, brightness, alpha];
 
                 for l in rectangle_to_lines(room_rectangle).iter() {
                     line(color, 0.5, *l, context.transform, gl);
                 }
+
+                // Names help level designers tell rooms apart; they are not part of the game
+                if camera.mode == CameraMode::EditorMode {
+                    if let Some(properties) = room_properties.get(entity) {
+                        let [x, y, _width, _height] = room_rectangle;
+                        draw_text(&properties.name, x + 4.0, y + 4.0, 2.0, color, context.transform, gl);
+                    }
+                }
             });
         }
+    }
+}
+
+/// Draws the terrain boxes of one layer; run once per layer, so that the background is drawn
+/// behind the objects in a room and the foreground over them.
+pub struct DrawTerrain<'a> {
+    pub gl_graphics: &'a mut GlGraphics,
+    pub render_args: RenderArgs,
+    pub layer: TerrainLayer,
+}
 
-        // Draw terrain entities in rooms
-        for (_entity, position, size, animation, in_room) in (&*entities, &positions, &sizes, &animations, &in_rooms).join() {
-            let room_position = match positions.get(entities.entity(in_room.room_entity)) {
+impl <'a, 'b> System<'a> for DrawTerrain<'b> {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, Animation<RoomAnimation>>,
+        ReadStorage<'a, InRoom>,
+        ReadStorage<'a, TerrainLayer>,
+        ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
+    );
+
+    fn run(&mut self, (entities, positions, sizes, animations, in_rooms, terrain_layers, camera,
+        interpolation): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        // Decorative layers are drawn dimmer than the solid terrain, and the foreground lets the
+        // objects behind it show through
+        let (fill, outline) = match self.layer {
+            TerrainLayer::Background => ([0.08, 0.08, 0.1, 1.0], 0.5),
+            TerrainLayer::Collision => ([0.05, 0.05, 0.05, 1.0], 1.0),
+            TerrainLayer::Foreground => ([0.1, 0.1, 0.08, 0.6], 0.5),
+        };
+
+        for (entity, position, size, animation, in_room) in (&*entities, &positions, &sizes, &animations, &in_rooms).join() {
+            if terrain_layers.get(entity).cloned().unwrap_or_default() != self.layer {
+                continue;
+            }
+
+            let room_position = match positions.get(in_room.room_entity) {
                 Some(room_position) => room_position,
                 None => continue,
             };
@@ -337,15 +577,15 @@ impl <'a, 'b> System<'a> for DrawRooms<'b> {
                 size.width, size.height,
             ];
 
-            let brightness = 0.25 + 0.75 * ((32 - animation.current) as f32 / 32.0);
+            let brightness = outline * (0.25 + 0.75 * ((32 - animation.current) as f32 / 32.0));
 
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::{Rectangle, Line};
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 //rectangle([0.05, 0.05, 0.05, 1.0], terrain_rectangle, context.transform, gl);
-                Rectangle::new([0.05, 0.05, 0.05, alpha])
+                Rectangle::new([fill[0], fill[1], fill[2], fill[3] * alpha])
                     .draw(terrain_rectangle, &context.draw_state, context.transform, gl);
 
                 let color = [brightness, brightness, brightness, alpha];
@@ -373,16 +613,24 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
         ReadStorage<'a, CollisionSet>,
         ReadStorage<'a, Jump>,
         ReadStorage<'a, Aim>,
+        ReadStorage<'a, PreviousPosition>,
+        ReadExpect<'a, Interpolation>,
         ReadExpect<'a, Camera>,
     );
 
-    fn run(&mut self, (entities, positions, shapes, in_rooms, collision_sets, jumps, aims, camera): Self::SystemData) {
-        for (_entity, position, shape, in_room) in (&*entities, &positions, &shapes, &in_rooms).join() {
+    fn run(&mut self, (entities, positions, shapes, in_rooms, collision_sets, jumps, aims,
+        previous_positions, interpolation, camera): Self::SystemData)
+    {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        for (entity, position, shape, in_room) in (&*entities, &positions, &shapes, &in_rooms).join() {
             if shape.class != ShapeClass::Ball {
                 continue
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let position = position.interpolate(previous_positions.get(entity), interpolation.alpha);
+
+            let room_entity = in_room.room_entity;
 
             let room_position = match positions.get(room_entity) {
                 Some(room_position) => room_position,
@@ -392,7 +640,7 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::{Transformed, CircleArc};
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 let size = shape.size;
                 let rect = [position.x - size, position.y - size, size * 2.0, size * 2.0];
@@ -403,12 +651,14 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
             });
         }
 
-        for (_entity, position, in_room, collision_set) in (&*entities, &positions, &in_rooms, &collision_sets).join() {
+        for (entity, position, in_room, collision_set) in (&*entities, &positions, &in_rooms, &collision_sets).join() {
             if collision_set.time_since_collision > 0.2 {
                 continue;
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let position = position.interpolate(previous_positions.get(entity), interpolation.alpha);
+
+            let room_entity = in_room.room_entity;
 
             let room_position = match positions.get(room_entity) {
                 Some(room_position) => room_position,
@@ -426,19 +676,21 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::line;
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 line([0.0, 1.0, 0.0, collision_alpha * alpha],
                      0.5, [x1, y1, x2, y2], context.transform, gl);
             });
         }
 
-        for (_entity, position, in_room, jump) in (&*entities, &positions, &in_rooms, &jumps).join() {
+        for (entity, position, in_room, jump) in (&*entities, &positions, &in_rooms, &jumps).join() {
             if jump.cooldown <= 0.0 {
                 continue;
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let position = position.interpolate(previous_positions.get(entity), interpolation.alpha);
+
+            let room_entity = in_room.room_entity;
 
             let room_position = match positions.get(room_entity) {
                 Some(room_position) => room_position,
@@ -448,7 +700,7 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::{Transformed, circle_arc};
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 let rect = [position.x - 7.0, position.y - 7.0, 14.0, 14.0];
                 let context = context.trans(room_position.x, room_position.y);
@@ -461,12 +713,14 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
         }
 
         // Draw aiming reticule
-        for (_entity, position, in_room, aim) in (&*entities, &positions, &in_rooms, &aims).join() {
+        for (entity, position, in_room, aim) in (&*entities, &positions, &in_rooms, &aims).join() {
             if !aim.aiming {
                 continue;
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let position = position.interpolate(previous_positions.get(entity), interpolation.alpha);
+
+            let room_entity = in_room.room_entity;
 
             let room_position = match positions.get(room_entity) {
                 Some(room_position) => room_position,
@@ -482,7 +736,7 @@ impl <'a, 'b> System<'a> for DrawBalls<'b> {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::line;
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 line([1.0, 0.3, 0.3, 1.0 * alpha], 0.5,
                      [p1.x, p1.y, p2.x, p2.y], context.transform, gl);
@@ -521,16 +775,24 @@ impl <'a, 'b> System<'a> for DrawChainLinks<'b> {
         ReadStorage<'a, Shape>,
         ReadStorage<'a, InRoom>,
         ReadStorage<'a, ChainLink>,
+        ReadStorage<'a, PreviousPosition>,
+        ReadExpect<'a, Interpolation>,
         ReadExpect<'a, Camera>,
     );
 
-    fn run(&mut self, (entities, positions, shapes, in_rooms, chain_links, camera): Self::SystemData) {
-        for (_entity, position, shape, in_room, chain_link) in (&*entities, &positions, &shapes, &in_rooms, &chain_links).join() {
+    fn run(&mut self, (entities, positions, shapes, in_rooms, chain_links, previous_positions,
+        interpolation, camera): Self::SystemData)
+    {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        for (entity, position, shape, in_room, chain_link) in (&*entities, &positions, &shapes, &in_rooms, &chain_links).join() {
             if shape.class != ShapeClass::ChainLink {
                 continue;
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let position = position.interpolate(previous_positions.get(entity), interpolation.alpha);
+
+            let room_entity = in_room.room_entity;
 
             let room_position = match positions.get(room_entity) {
                 Some(room_position) => room_position,
@@ -540,7 +802,7 @@ impl <'a, 'b> System<'a> for DrawChainLinks<'b> {
             self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
                 use graphics::{Transformed, CircleArc};
 
-                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity));
+                let (context, alpha) = camera.apply_transform(gl, context, Some(in_room.room_entity.id()));
 
                 let size = shape.size;
                 let rect = [position.x - size, position.y - size, size * 2.0, size * 2.0];
@@ -566,6 +828,154 @@ impl <'a, 'b> System<'a> for DrawChainLinks<'b> {
     }
 }
 
+/// Draws the links between rooms as arrows in editor camera mode, and the link being drawn with
+/// the link tool.
+pub struct DrawRoomLinks<'a> {
+    pub gl_graphics: &'a mut GlGraphics,
+    pub render_args: RenderArgs,
+}
+
+/// Lines of an arrow from `from` to `to`, shifted a little to the side so that the arrows of two
+/// rooms linked both ways do not overlap.
+fn arrow_lines(from: (f64, f64), to: (f64, f64)) -> Vec<[f64; 4]> {
+    let direction = Vector2::new(to.0 - from.0, to.1 - from.1);
+
+    if direction.norm() < 1.0 {
+        return Vec::new();
+    }
+
+    let direction = direction.normalize();
+    let side = Vector2::new(-direction.y, direction.x) * 4.0;
+    let (from, to) = (Vector2::new(from.0, from.1) + side, Vector2::new(to.0, to.1) + side);
+
+    let head_left = to - direction * 10.0 + side;
+    let head_right = to - direction * 10.0 - side;
+
+    vec![
+        [from.x, from.y, to.x, to.y],
+        [head_left.x, head_left.y, to.x, to.y],
+        [head_right.x, head_right.y, to.x, to.y],
+    ]
+}
+
+fn room_center(room: Entity, positions: &ReadStorage<Position>, sizes: &ReadStorage<Size>) -> Option<(f64, f64)> {
+    match (positions.get(room), sizes.get(room)) {
+        (Some(position), Some(size)) => Some((position.x + size.width / 2.0, position.y + size.height / 2.0)),
+        _ => None,
+    }
+}
+
+impl <'a, 'b> System<'a> for DrawRoomLinks<'b> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, InputState>,
+        ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
+        ReadExpect<'a, EditorController>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, RoomLink>,
+    );
+
+    fn run(&mut self, (entities, input_state, camera, interpolation, editor_controller, positions, sizes,
+        room_links): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        let mut arrows = Vec::new();
+
+        if camera.mode == CameraMode::EditorMode {
+            for (entity, room_link) in (&*entities, &room_links).join() {
+                let from = room_center(entity, &positions, &sizes);
+                let to = room_center(room_link.target_room, &positions, &sizes);
+
+                if let (Some(from), Some(to)) = (from, to) {
+                    arrows.push((from, to, [0.9, 0.6, 0.2, 0.8]));
+                }
+            }
+        }
+
+        if let Some(from) = editor_controller.linking_from.and_then(|room| room_center(room, &positions, &sizes)) {
+            arrows.push((from, input_state.world_mouse.position, [1.0, 0.8, 0.3, 1.0]));
+        }
+
+        if arrows.is_empty() {
+            return;
+        }
+
+        self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
+            use graphics::line;
+
+            let (context, _alpha) = camera.apply_transform(gl, context, None);
+
+            for &(from, to, color) in &arrows {
+                for l in arrow_lines(from, to).iter() {
+                    line(color, 0.75, *l, context.transform, gl);
+                }
+            }
+        });
+    }
+}
+
+/// Draws the editor grid over the whole screen in editor camera mode, and otherwise only inside
+/// the focused room.
+pub struct DrawGrid<'a> {
+    pub gl_graphics: &'a mut GlGraphics,
+    pub render_args: RenderArgs,
+}
+
+impl <'a, 'b> System<'a> for DrawGrid<'b> {
+    type SystemData = (
+        ReadExpect<'a, InputState>,
+        ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
+        ReadExpect<'a, Screen>,
+        ReadExpect<'a, Grid>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+    );
+
+    fn run(&mut self, (input_state, camera, interpolation, screen, grid, positions, sizes): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
+
+        let area = match camera.mode {
+            CameraMode::EditorMode => [camera.x, camera.y, screen.width, screen.height],
+            CameraMode::Normal => {
+                let room = input_state.room_focused
+                    .and_then(|room| positions.get(room).and_then(|position| sizes.get(room).map(|size| (position, size))));
+
+                match room {
+                    Some((position, size)) => [position.x, position.y, size.width, size.height],
+                    None => return,
+                }
+            },
+        };
+
+        let cell_size = grid.cell_size as f64;
+        let [x, y, width, height] = area;
+
+        // Fainter when snapping is off, since the grid is only a guide then
+        let alpha = if grid.snapping { 0.15 } else { 0.05 };
+
+        self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
+            use graphics::line;
+
+            let (context, _alpha) = camera.apply_transform(gl, context, None);
+
+            let mut line_x = (x / cell_size).ceil() * cell_size;
+            while line_x <= x + width {
+                line([0.5, 0.5, 0.5, alpha], 0.25, [line_x, y, line_x, y + height], context.transform, gl);
+                line_x += cell_size;
+            }
+
+            let mut line_y = (y / cell_size).ceil() * cell_size;
+            while line_y <= y + height {
+                line([0.5, 0.5, 0.5, alpha], 0.25, [x, line_y, x + width, line_y], context.transform, gl);
+                line_y += cell_size;
+            }
+        });
+    }
+}
+
 pub struct DrawSelectionBox<'a> {
     pub gl_graphics: &'a mut GlGraphics,
     pub render_args: RenderArgs,
@@ -575,18 +985,66 @@ impl <'a, 'b> System<'a> for DrawSelectionBox<'b> {
     type SystemData = (
         ReadExpect<'a, InputState>,
         ReadExpect<'a, Camera>,
+        ReadExpect<'a, Interpolation>,
+        ReadExpect<'a, EditorController>,
+        ReadExpect<'a, Grid>,
+        ReadExpect<'a, Prefabs>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, InRoom>,
     );
 
-    fn run(&mut self, (input_state, camera): Self::SystemData) {
+    fn run(&mut self, (input_state, camera, interpolation, editor_controller, grid, prefabs, positions, sizes,
+        in_rooms): Self::SystemData) {
+        let camera = camera.interpolate(interpolation.alpha);
+
         self.gl_graphics.draw(self.render_args.viewport(), |context, gl| {
-            if let Some(selection_box) = input_state.world_mouse.selection_box() {
-                use graphics::{rectangle, line};
+            use graphics::{rectangle, line};
+
+            let (context, _alpha) = camera.apply_transform(gl, context, None);
 
-                let (context, _alpha) = camera.apply_transform(gl, context, None);
+            if editor_controller.tool == EditorTool::Select {
+                // Preview the drag in progress, snapped the same way it will be applied
+                let rect = match editor_controller.drag {
+                    Some(ref drag) => Some(drag.bounds_at(input_state.world_mouse.position, &grid)),
+                    None => editor_controller.selected
+                        .and_then(|selected| world_bounds(selected, &positions, &sizes, &in_rooms)),
+                };
 
+                if let Some(rect) = rect {
+                    for l in rectangle_to_lines(rect).iter() {
+                        line([0.25, 0.5, 1.0, 1.0], 0.5, *l, context.transform, gl);
+                    }
+
+                    let [x, y, width, height] = rect;
+
+                    for &handle_x in &[x, x + width / 2.0, x + width] {
+                        for &handle_y in &[y, y + height / 2.0, y + height] {
+                            let handle = [handle_x - HANDLE_SIZE / 2.0, handle_y - HANDLE_SIZE / 2.0, HANDLE_SIZE, HANDLE_SIZE];
+                            rectangle([0.25, 0.5, 1.0, 1.0], handle, context.transform, gl);
+                        }
+                    }
+                }
+            } else if editor_controller.tool == EditorTool::Place {
+                use graphics::CircleArc;
+
+                // Preview the object to place, where it would go
+                let kind = palette(&prefabs).get(editor_controller.palette_index).cloned();
+                let size = kind
+                    .and_then(|kind| prefabs.get(kind.prefab_name()).and_then(|prefab| prefab.shape))
+                    .map_or(10.0, |shape| shape.size);
+
+                if input_state.room_focused.is_some() {
+                    let (x, y) = input_state.world_mouse.position;
+                    let rect = [x - size, y - size, size * 2.0, size * 2.0];
+
+                    CircleArc::new([0.25, 1.0, 0.25, 0.5], 0.5, 0.0, 1.9999 * ::std::f64::consts::PI)
+                        .draw(rect, &context.draw_state, context.transform, gl);
+                }
+            } else if let Some(selection_box) = input_state.world_mouse.selection_box() {
                 let rect = selection_box
                     .to_rectangle()
-                    .snap_to_grid(16)
+                    .snap_to(&grid)
                     .to_array();
 
                 rectangle([0.25, 1.0, 0.25, 0.01], rect, context.transform, gl);
@@ -598,15 +1056,15 @@ impl <'a, 'b> System<'a> for DrawSelectionBox<'b> {
     }
 }
 
-pub struct SetCameraTarget<'a> {
-    pub gl_graphics: &'a mut GlGraphics,
-    pub render_args: RenderArgs,
-}
+/// Part of the simulation rather than of rendering, since the camera decides where the mouse
+/// points to in the world; replays would otherwise depend on the frame rate.
+pub struct SetCameraTarget;
 
-impl <'a, 'b> System<'a> for SetCameraTarget<'b> {
+impl <'a> System<'a> for SetCameraTarget {
     type SystemData = (
         Entities<'a>,
         WriteExpect<'a, Camera>,
+        ReadExpect<'a, Screen>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Size>,
         ReadStorage<'a, InRoom>,
@@ -614,7 +1072,7 @@ impl <'a, 'b> System<'a> for SetCameraTarget<'b> {
         ReadStorage<'a, PlayerController>,
     );
 
-    fn run(&mut self, (entities, mut camera, positions, sizes, in_rooms, shifters, player_controllers): Self::SystemData) {
+    fn run(&mut self, (entities, mut camera, screen, positions, sizes, in_rooms, shifters, player_controllers): Self::SystemData) {
         // Camera panning overrides any other camera targets
         if camera.panning_direction.is_some() {
             return;
@@ -626,7 +1084,7 @@ impl <'a, 'b> System<'a> for SetCameraTarget<'b> {
                 // Get the first entity that has a PlayerController
                 // FIXME: Maybe add a specific 'focusable' component instead?
                 for (entity, position, in_room, _player_controller) in (&*entities, &positions, &in_rooms, &player_controllers).join() {
-                    let room_entity = entities.entity(in_room.room_entity);
+                    let room_entity = in_room.room_entity;
 
                     let room_position = match positions.get(room_entity) {
                         Some(room_position) => room_position,
@@ -638,8 +1096,8 @@ impl <'a, 'b> System<'a> for SetCameraTarget<'b> {
                         None => continue,
                     };
 
-                    let screen_halfwidth = self.render_args.width as f64 / 2.0;
-                    let screen_halfheight = self.render_args.height as f64 / 2.0;
+                    let screen_halfwidth = screen.width / 2.0;
+                    let screen_halfheight = screen.height / 2.0;
 
                     camera.target_y = room_position.y + position.y - screen_halfheight;
 
@@ -675,13 +1133,13 @@ impl <'a, 'b> System<'a> for SetCameraTarget<'b> {
                     if let Some(shifter) = shifters.get(entity) {
                         if shifter.sensing && camera.phase_overlay.is_none() {
                             if let Some(target_room) = shifter.target_room {
-                                if let Some(target_room_position) = positions.get(entities.entity(target_room)) {
+                                if let Some(target_room_position) = positions.get(target_room) {
                                     camera.phase_overlay = Some(PhaseOverlay {
                                         sphere_center: (room_position.x + position.x, room_position.y + position.y),
                                         sphere_size: 0.0,
                                         sphere_state: PhaseSphereState::Forming,
                                         source_room: room_entity.id(),
-                                        target_room,
+                                        target_room: target_room.id(),
                                         target_room_offset: (
                                             target_room_position.x - room_position.x,
                                             target_room_position.y - room_position.y,
@@ -744,6 +1202,9 @@ impl <'a> System<'a> for UpdateCamera {
     );
 
     fn run(&mut self, (mut camera, delta_time): Self::SystemData) {
+        camera.previous_x = camera.x;
+        camera.previous_y = camera.y;
+
         // Edge panning is enabled while dragging with the mouse
         if let Some(panning_direction) = camera.panning_direction {
             camera.target_x += panning_direction.0 * delta_time.dt * 400.0;
@@ -796,35 +1257,22 @@ impl <'a> System<'a> for UpdateCamera {
     }
 }
 
-pub struct SetScreenSize<'a> {
-    pub gl_graphics: &'a mut GlGraphics,
-    pub render_args: RenderArgs,
-}
-
-impl <'a, 'b> System<'a> for SetScreenSize<'b> {
-    type SystemData = WriteExpect<'a, Screen>;
-
-    fn run(&mut self, mut screen: Self::SystemData) {
-        screen.width = self.render_args.width as f64;
-        screen.height = self.render_args.height as f64;
-    }
-}
-
 pub fn run_draw_systems(specs_world: &mut World,
                         gl_graphics: &mut GlGraphics,
                         render_args: RenderArgs) {
-    SetScreenSize { gl_graphics, render_args }
+    ClearScreen { gl_graphics, render_args }
         .run_now(&mut specs_world.res);
 
-    SetCameraTarget { gl_graphics, render_args }
+    DrawRooms { gl_graphics, render_args }
         .run_now(&mut specs_world.res);
 
-    UpdateCamera.run_now(&mut specs_world.res);
+    DrawGrid { gl_graphics, render_args }
+        .run_now(&mut specs_world.res);
 
-    ClearScreen { gl_graphics, render_args }
+    DrawTerrain { gl_graphics, render_args, layer: TerrainLayer::Background }
         .run_now(&mut specs_world.res);
 
-    DrawRooms { gl_graphics, render_args }
+    DrawTerrain { gl_graphics, render_args, layer: TerrainLayer::Collision }
         .run_now(&mut specs_world.res);
 
     DrawBalls { gl_graphics, render_args }
@@ -833,9 +1281,15 @@ pub fn run_draw_systems(specs_world: &mut World,
     DrawChainLinks { gl_graphics, render_args }
         .run_now(&mut specs_world.res);
 
+    DrawTerrain { gl_graphics, render_args, layer: TerrainLayer::Foreground }
+        .run_now(&mut specs_world.res);
+
     DrawPhaseSphere { gl_graphics, render_args }
         .run_now(&mut specs_world.res);
 
+    DrawRoomLinks { gl_graphics, render_args }
+        .run_now(&mut specs_world.res);
+
     DrawSelectionBox { gl_graphics, render_args }
         .run_now(&mut specs_world.res);
 }
@@ -1,24 +1,46 @@
-use specs::prelude::{System, Entity, Entities, ReadExpect, WriteExpect, LazyUpdate, Builder};
+use specs::prelude::{World, System, Entity, Entities, ReadStorage, ReadExpect, WriteExpect, LazyUpdate, Builder, Join, VecStorage};
 use specs::world::EntitiesRes;
-use specs::saveload::{U64Marker, MarkedBuilder};
-use std::collections::VecDeque;
+use specs::saveload::{U64Marker, Marker, MarkedBuilder};
+use std::collections::{VecDeque, HashMap, HashSet};
 
-use input;
 use draw;
-use shift;
 use physics;
-use animate;
-use control;
+use saveload::{self, CurrentLevel, DestroyEntity};
+use saveformat::EntityRecord;
+use prefab::Prefabs;
+use shift::RoomLink;
+use input::PlayerController;
+use control::ChainLink;
+use error::Error;
+
+/// How many edits can be undone
+const HISTORY_LIMIT: usize = 100;
 
 
 pub struct EditorController {
     edit_events: VecDeque<EditEvent>,
+    pub tool: EditorTool,
+    /// Room or terrain box picked with the select tool
+    pub selected: Option<Entity>,
+    pub drag: Option<Drag>,
+    /// Room that a link is being drawn from with the link tool
+    pub linking_from: Option<Entity>,
+    /// Index into the `palette` of the object placed by the place tool
+    pub palette_index: usize,
+    /// Layer that new terrain boxes are drawn on
+    pub layer: draw::TerrainLayer,
 }
 
 impl EditorController {
     pub fn new() -> Self {
         EditorController {
             edit_events: VecDeque::with_capacity(16),
+            tool: EditorTool::Draw,
+            selected: None,
+            drag: None,
+            linking_from: None,
+            palette_index: 0,
+            layer: draw::TerrainLayer::Collision,
         }
     }
 
@@ -29,81 +51,860 @@ impl EditorController {
 
 pub enum EditEvent {
     CreateRoom { x: f64, y: f64, width: f64, height: f64 },
-    CreateTerrainBox { x: f64, y: f64, width: f64, height: f64, room_entity: Entity },
+    CreateTerrainBox { x: f64, y: f64, width: f64, height: f64, room_entity: Entity, layer: draw::TerrainLayer },
+    /// Delete a room or terrain box, along with everything inside or attached to it
+    Delete { entity: Entity },
+    /// Move or resize a room or terrain box; the bounds are in world coordinates
+    SetBounds { entity: Entity, x: f64, y: f64, width: f64, height: f64 },
+    /// Make objects in `room` shift to `target_room`, or nowhere
+    LinkRoom { room: Entity, target_room: Option<Entity> },
+    /// Add a spawn point for an object, relative to its room, and spawn the object
+    PlaceObject { kind: SpawnKind, x: f64, y: f64, room_entity: Entity },
+}
+
+/// What dragging with the `Select` action does in the editor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorTool {
+    /// Draw rooms, or terrain boxes inside rooms
+    Draw,
+    /// Pick a room or terrain box, and move or resize it
+    Select,
+    /// Drag from one room to another to link them for phase shifting
+    Link,
+    /// Place the selected object of the palette inside a room
+    Place,
 }
 
+impl EditorTool {
+    pub fn next(&self) -> Self {
+        match *self {
+            EditorTool::Draw => EditorTool::Select,
+            EditorTool::Select => EditorTool::Link,
+            EditorTool::Link => EditorTool::Place,
+            EditorTool::Place => EditorTool::Draw,
+        }
+    }
+}
 
-pub struct CreateRoom;
+/// How close to an edge of the selection, in pixels, a drag resizes instead of moving it
+pub const HANDLE_SIZE: f64 = 6.0;
 
-fn create_room(
-    entities: &EntitiesRes, lazy_update: &LazyUpdate,
-    x: f64, y: f64, width: f64, height: f64
-) {
-    let entity = lazy_update.create_entity(entities)
+const MIN_GRID_SIZE: i32 = 4;
+const MAX_GRID_SIZE: i32 = 128;
+
+/// The grid that rooms and terrain boxes are drawn, moved and resized on. It is a resource saved
+/// along with the level.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct Grid {
+    /// Size of a grid cell, in pixels
+    pub cell_size: i32,
+    pub snapping: bool,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            cell_size: 16,
+            snapping: true,
+        }
+    }
+}
+
+impl Grid {
+    /// The grid line closest to `value`, or `value` itself with snapping disabled.
+    pub fn snap(&self, value: f64) -> f64 {
+        if self.snapping {
+            let cell_size = self.cell_size as f64;
+            (value / cell_size).round() * cell_size
+        } else {
+            value
+        }
+    }
+
+    /// The smallest width or height a room or terrain box can be given.
+    pub fn min_size(&self) -> f64 {
+        if self.snapping { self.cell_size as f64 } else { 1.0 }
+    }
+
+    pub fn smaller(&self) -> Self {
+        Grid { cell_size: (self.cell_size / 2).max(MIN_GRID_SIZE), .. *self }
+    }
+
+    pub fn larger(&self) -> Self {
+        Grid { cell_size: (self.cell_size * 2).min(MAX_GRID_SIZE), .. *self }
+    }
+
+    /// The grid with its cell size brought into the range the editor supports.
+    pub fn clamped(&self) -> Self {
+        Grid { cell_size: self.cell_size.max(MIN_GRID_SIZE).min(MAX_GRID_SIZE), .. *self }
+    }
+}
+
+/// The edges of the selection that a drag moves; all of them when moving it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DragHandle {
+    pub left: bool,
+    pub right: bool,
+    pub top: bool,
+    pub bottom: bool,
+}
+
+impl DragHandle {
+    pub fn is_move(&self) -> bool {
+        self.left && self.right && self.top && self.bottom
+    }
+
+    /// The handle of `bounds` under the point (`x`, `y`): edges and corners resize, and the inside
+    /// moves.
+    pub fn at(bounds: [f64; 4], x: f64, y: f64) -> Option<Self> {
+        let [left, top, width, height] = bounds;
+        let (right, bottom) = (left + width, top + height);
+
+        if x < left - HANDLE_SIZE || x > right + HANDLE_SIZE || y < top - HANDLE_SIZE || y > bottom + HANDLE_SIZE {
+            return None;
+        }
+
+        let handle = DragHandle {
+            left: (x - left).abs() <= HANDLE_SIZE,
+            right: (x - right).abs() <= HANDLE_SIZE,
+            top: (y - top).abs() <= HANDLE_SIZE,
+            bottom: (y - bottom).abs() <= HANDLE_SIZE,
+        };
+
+        if handle.left || handle.right || handle.top || handle.bottom {
+            Some(handle)
+        } else if x > left && x < right && y > top && y < bottom {
+            Some(DragHandle { left: true, right: true, top: true, bottom: true })
+        } else {
+            None
+        }
+    }
+}
+
+/// A move or resize in progress with the select tool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drag {
+    pub entity: Entity,
+    pub handle: DragHandle,
+    /// World bounds of the entity when the drag started
+    pub bounds: [f64; 4],
+    /// Where the mouse was when the drag started
+    pub from: (f64, f64),
+}
+
+impl Drag {
+    /// World bounds of the entity with the mouse at `to`, with the dragged edges snapped to
+    /// `grid`; a resized box never gets smaller than one cell.
+    pub fn bounds_at(&self, to: (f64, f64), grid: &Grid) -> [f64; 4] {
+        let [x, y, width, height] = self.bounds;
+        let (dx, dy) = (to.0 - self.from.0, to.1 - self.from.1);
+
+        if self.handle.is_move() {
+            return [grid.snap(x + dx), grid.snap(y + dy), width, height];
+        }
+
+        let (mut left, mut top, mut right, mut bottom) = (x, y, x + width, y + height);
+
+        if self.handle.left {
+            left = grid.snap(left + dx).min(right - grid.min_size());
+        }
+
+        if self.handle.right {
+            right = grid.snap(right + dx).max(left + grid.min_size());
+        }
+
+        if self.handle.top {
+            top = grid.snap(top + dy).min(bottom - grid.min_size());
+        }
+
+        if self.handle.bottom {
+            bottom = grid.snap(bottom + dy).max(top + grid.min_size());
+        }
+
+        [left, top, right - left, bottom - top]
+    }
+}
+
+/// Where a room's content at `position` must go to be inside a room of `width` by `height`, and
+/// the size it must shrink to if it is terrain larger than the room. Terrain is placed by its top
+/// left corner, and objects by their center.
+fn clamp_into_room(position: draw::Position, size: Option<&draw::Size>, shape: Option<&draw::Shape>,
+                   width: f64, height: f64) -> (draw::Position, Option<draw::Size>)
+{
+    let clamp = |value: f64, min: f64, max: f64| value.min(max).max(min);
+
+    match (size, shape) {
+        (Some(size), _) => {
+            let size = draw::Size { width: size.width.min(width), height: size.height.min(height) };
+            let position = draw::Position {
+                x: clamp(position.x, 0.0, width - size.width),
+                y: clamp(position.y, 0.0, height - size.height),
+            };
+
+            (position, Some(size))
+        },
+        (None, Some(shape)) => {
+            let radius = shape.size.min(width / 2.0).min(height / 2.0);
+            let position = draw::Position {
+                x: clamp(position.x, radius, width - radius),
+                y: clamp(position.y, radius, height - radius),
+            };
+
+            (position, None)
+        },
+        (None, None) => (draw::Position { x: clamp(position.x, 0.0, width), y: clamp(position.y, 0.0, height) }, None),
+    }
+}
+
+/// Where the coordinates of `entity` start in the world: its room's position, or the origin.
+fn room_offset(entity: Entity, positions: &ReadStorage<draw::Position>, in_rooms: &ReadStorage<physics::InRoom>) -> (f64, f64) {
+    in_rooms.get(entity)
+        .and_then(|in_room| positions.get(in_room.room_entity))
+        .map_or((0.0, 0.0), |room_position| (room_position.x, room_position.y))
+}
+
+/// Bounds of a room or terrain box in world coordinates, as [x, y, width, height].
+pub fn world_bounds(
+    entity: Entity, positions: &ReadStorage<draw::Position>, sizes: &ReadStorage<draw::Size>,
+    in_rooms: &ReadStorage<physics::InRoom>
+) -> Option<[f64; 4]> {
+    let (position, size) = match (positions.get(entity), sizes.get(entity)) {
+        (Some(position), Some(size)) => (position, size),
+        _ => return None,
+    };
+
+    let (offset_x, offset_y) = room_offset(entity, positions, in_rooms);
+
+    Some([position.x + offset_x, position.y + offset_y, size.width, size.height])
+}
+
+/// A reversible edit: the saved state of every entity it touched, before and after.
+///
+/// Entities are identified by their markers, so that a command still applies after undo and redo
+/// have deleted and re-created the entities it refers to.
+#[derive(Debug, Clone)]
+pub struct EditCommand {
+    before: Vec<EntityRecord>,
+    after: Vec<EntityRecord>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryRequest {
+    Undo,
+    Redo,
+}
+
+/// Undo and redo stacks of the editor.
+///
+/// Edit systems apply their changes through `LazyUpdate`, and report the entities they change
+/// with `touch` and the ones they create with `create`. After the systems ran, and before the
+/// world is maintained, `begin_command` saves the touched entities; after the changes have been
+/// applied, `finish_command` saves them again, and pushes both states as one command.
+#[derive(Debug, Default)]
+pub struct EditHistory {
+    undo_stack: VecDeque<EditCommand>,
+    redo_stack: Vec<EditCommand>,
+    touched: Vec<Entity>,
+    created: Vec<Entity>,
+    before: Option<Vec<EntityRecord>>,
+    pub request: Option<HistoryRequest>,
+}
+
+impl EditHistory {
+    /// Record that the current edit changes or deletes `entity`.
+    pub fn touch(&mut self, entity: Entity) {
+        if !self.touched.contains(&entity) {
+            self.touched.push(entity);
+        }
+    }
+
+    /// Record that the current edit creates `entity`.
+    pub fn create(&mut self, entity: Entity) {
+        self.created.push(entity);
+    }
+
+    fn push(&mut self, command: EditCommand) {
+        if self.undo_stack.len() >= HISTORY_LIMIT {
+            self.undo_stack.pop_front();
+        }
+
+        self.undo_stack.push_back(command);
+        self.redo_stack.clear();
+    }
+}
+
+/// Save the entities that this tick's edits are about to change.
+pub fn begin_command(world: &World) {
+    let mut history = world.write_resource::<EditHistory>();
+
+    if history.touched.is_empty() && history.created.is_empty() {
+        return;
+    }
+
+    let before = saveload::snapshot_entities(world, &history.touched);
+
+    match before {
+        Ok(before) => history.before = Some(before),
+        Err(error) => {
+            eprintln!("Error: cannot record edit for undo: {}", error);
+            history.touched.clear();
+            history.created.clear();
+        },
+    }
+}
+
+/// Record this tick's edits as one command, now that they have been applied.
+pub fn finish_command(world: &World) {
+    let mut history = world.write_resource::<EditHistory>();
+
+    let before = match history.before.take() {
+        Some(before) => before,
+        None => return,
+    };
+
+    let mut changed: Vec<Entity> = history.touched.drain(..).collect();
+    changed.extend(history.created.drain(..));
+
+    // Deleted entities are left out of the after state
+    let changed: Vec<Entity> = {
+        let destroy_entities = world.read_storage::<DestroyEntity>();
+
+        changed.into_iter()
+            .filter(|&entity| world.is_alive(entity) && destroy_entities.get(entity).is_none())
+            .collect()
+    };
+
+    match saveload::snapshot_entities(world, &changed) {
+        Ok(after) => history.push(EditCommand { before, after }),
+        Err(error) => eprintln!("Error: cannot record edit for undo: {}", error),
+    }
+
+    world.write_resource::<CurrentLevel>().modified = true;
+}
+
+/// Undo or redo the last command, if requested.
+pub fn handle_history_request(world: &mut World) {
+    let request = world.write_resource::<EditHistory>().request.take();
+
+    let command = {
+        let mut history = world.write_resource::<EditHistory>();
+
+        match request {
+            Some(HistoryRequest::Undo) => history.undo_stack.pop_back(),
+            Some(HistoryRequest::Redo) => history.redo_stack.pop(),
+            None => return,
+        }
+    };
+
+    let command = match command {
+        Some(command) => command,
+        None => return,
+    };
+
+    let result = match request {
+        Some(HistoryRequest::Undo) => saveload::restore_entities(world, &command.after, &command.before),
+        _ => saveload::restore_entities(world, &command.before, &command.after),
+    };
+
+    world.maintain();
+
+    if let Err(error) = result {
+        eprintln!("Error: cannot restore edit: {}", error);
+        return;
+    }
+
+    world.write_resource::<CurrentLevel>().modified = true;
+
+    let mut history = world.write_resource::<EditHistory>();
+
+    match request {
+        Some(HistoryRequest::Undo) => history.redo_stack.push(command),
+        _ => history.undo_stack.push_back(command),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClipboardRequest {
+    /// Copy a room and its contents to the clipboard
+    Copy(Entity),
+    /// Paste the clipboard with the top-left corner of the room at the given world position
+    Paste(f64, f64),
+    /// Copy a room, and paste it right next to the original
+    Duplicate(Entity),
+}
+
+/// A copied room. Like undo, copy and paste work on the saved form of entities, so that pasting
+/// creates fresh entities with fresh markers.
+#[derive(Debug, Default)]
+pub struct Clipboard {
+    /// The room, followed by its contents
+    records: Vec<EntityRecord>,
+    pub request: Option<ClipboardRequest>,
+}
+
+/// The saved state of `room` and its contents. The player, its spawn point and its hook are left
+/// out, since there is only one player.
+fn copy_room(world: &World, room: Entity) -> Result<Vec<EntityRecord>, Error> {
+    let copied = {
+        let entities = world.entities();
+        let in_rooms = world.read_storage::<physics::InRoom>();
+        let player_controllers = world.read_storage::<PlayerController>();
+        let spawn_points = world.read_storage::<SpawnPoint>();
+        let chain_links = world.read_storage::<ChainLink>();
+
+        let mut copied = vec![room];
+
+        for (entity, in_room) in (&*entities, &in_rooms).join() {
+            let is_player = player_controllers.get(entity).is_some() ||
+                spawn_points.get(entity).map_or(false, |spawn_point| spawn_point.kind == SpawnKind::Player);
+
+            if in_room.room_entity == room && !is_player && chain_links.get(entity).is_none() {
+                copied.push(entity);
+            }
+        }
+
+        copied
+    };
+
+    let records = saveload::snapshot_entities(world, &copied)?;
+
+    match records.first() {
+        Some(record) if record.components.room.is_some() => Ok(records),
+        _ => Err(format_err!("only rooms can be copied")),
+    }
+}
+
+/// Create a copy of the entities in `records` with fresh markers, with the room moved to (`x`,
+/// `y`). References between the copied entities lead to the copies, and references to other
+/// entities, such as a link to another room, are kept.
+fn paste_room(world: &mut World, records: &[EntityRecord], x: f64, y: f64) -> Result<Vec<Entity>, Error> {
+    let created: Vec<Entity> = records.iter()
+        .map(|_record| world.create_entity().marked::<U64Marker>().build())
+        .collect();
+
+    let (fresh_markers, live_markers) = {
+        let markers = world.read_storage::<U64Marker>();
+
+        let fresh_markers: HashMap<u64, U64Marker> = records.iter().zip(&created)
+            .filter_map(|(record, &entity)| markers.get(entity).map(|marker| (record.marker.id(), marker.clone())))
+            .collect();
+        let live_markers: HashSet<u64> = (&markers).join().map(|marker| marker.id()).collect();
+
+        (fresh_markers, live_markers)
+    };
+
+    let pasted: Vec<EntityRecord> = records.iter()
+        .map(|record| {
+            let mut components = record.components.clone();
+            components.remap_references(|marker| fresh_markers.get(&marker.id()).cloned());
+
+            if components.room.is_some() {
+                components.position = Some(draw::Position { x, y });
+            }
+
+            // The room that the copy was linked to may be gone, e.g. after loading another level
+            let dangling_link = components.room_link.as_ref()
+                .map_or(false, |room_link| !live_markers.contains(&room_link.target_room.id()));
+
+            if dangling_link {
+                components.room_link = None;
+            }
+
+            EntityRecord { marker: fresh_markers[&record.marker.id()].clone(), components }
+        })
+        .collect();
+
+    saveload::restore_entities(world, &[], &pasted)?;
+
+    Ok(created)
+}
+
+/// Copy or paste rooms, if requested. Pasted entities are created right away rather than through
+/// `LazyUpdate`, and recorded in the history like the other edits of this tick.
+pub fn handle_clipboard_request(world: &mut World) {
+    let request = match world.write_resource::<Clipboard>().request.take() {
+        Some(request) => request,
+        None => return,
+    };
+
+    let (room, paste_at) = match request {
+        ClipboardRequest::Copy(room) => (Some(room), None),
+        ClipboardRequest::Paste(x, y) => (None, Some((x, y))),
+        ClipboardRequest::Duplicate(room) => {
+            let bounds = world_bounds(room, &world.read_storage(), &world.read_storage(), &world.read_storage());
+            let cell_size = world.read_resource::<Grid>().cell_size as f64;
+
+            (Some(room), bounds.map(|[x, y, width, _height]| (x + width + cell_size, y)))
+        },
+    };
+
+    if let Some(room) = room {
+        match copy_room(world, room) {
+            Ok(records) => {
+                println!("Copied a room with {} objects", records.len() - 1);
+                world.write_resource::<Clipboard>().records = records;
+            },
+            Err(error) => {
+                eprintln!("Error: cannot copy room: {}", error);
+                return;
+            },
+        }
+    }
+
+    if let Some((x, y)) = paste_at {
+        let records = world.read_resource::<Clipboard>().records.clone();
+
+        if records.is_empty() {
+            return;
+        }
+
+        match paste_room(world, &records, x, y) {
+            Ok(created) => {
+                let mut history = world.write_resource::<EditHistory>();
+
+                for entity in created {
+                    history.create(entity);
+                }
+            },
+            Err(error) => eprintln!("Error: cannot paste room: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum SpawnKind {
+    Player,
+    Ball,
+    /// Any other prefab, by name
+    Prefab(String),
+}
+
+impl SpawnKind {
+    pub fn prefab_name(&self) -> &str {
+        match *self {
+            SpawnKind::Player => "player",
+            SpawnKind::Ball => "ball",
+            SpawnKind::Prefab(ref name) => name,
+        }
+    }
+}
+
+/// Every kind of object the place tool can place: the player, balls, and the other prefabs of
+/// dynamic objects, except chain links.
+pub fn palette(prefabs: &Prefabs) -> Vec<SpawnKind> {
+    let mut palette = vec![SpawnKind::Player, SpawnKind::Ball];
+
+    for (name, prefab) in prefabs.iter() {
+        let listed = palette.iter().any(|kind| kind.prefab_name() == name);
+
+        if !listed && prefab.velocity.is_some() && !prefab.chain_link {
+            palette.push(SpawnKind::Prefab(name.clone()));
+        }
+    }
+
+    palette
+}
+
+/// Part of a level: the place where a dynamic object appears when a new session starts.
+///
+/// Spawn points are positioned relative to their room, through `Position` and `InRoom`.
+#[derive(Component, Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[storage(VecStorage)]
+pub struct SpawnPoint {
+    pub kind: SpawnKind,
+}
+
+/// Create the dynamic object for a spawn point of the given `kind`. Nothing is created for a
+/// prefab that does not exist, e.g. because of a typo in a level, apart from a warning.
+pub fn spawn_object(
+    entities: &EntitiesRes, lazy_update: &LazyUpdate, prefabs: &Prefabs,
+    kind: &SpawnKind, x: f64, y: f64, room_entity: Entity
+) -> Option<Entity> {
+    let builder = match prefabs.spawn(kind.prefab_name(), entities, lazy_update) {
+        Some(builder) => builder,
+        None => {
+            eprintln!("Warning: there is no prefab called '{}', nothing was spawned", kind.prefab_name());
+            return None;
+        },
+    };
+
+    let entity = builder
         .with(draw::Position { x, y })
-        .with(draw::Size { width, height })
-        .with(physics::Room)
-        .with(animate::Animation::<animate::RoomAnimation>::new(32))
+        .with(physics::InRoom { room_entity })
         .marked::<U64Marker>()
         .build();
 
-    lazy_update.create_entity(entities)
-        .with(draw::Position { x: width / 2.0 + 5.0, y: height / 2.0 + 10.0 })
-        .with(draw::Shape { size: 10.0, class: draw::ShapeClass::Ball })
-        .with(physics::Velocity::default())
-        .with(physics::InRoom { room_entity: entity.id() })
+    Some(entity)
+}
+
+/// Add a spawn point to a level, and spawn its object right away.
+fn create_spawn_point(
+    entities: &EntitiesRes, lazy_update: &LazyUpdate, prefabs: &Prefabs, history: &mut EditHistory,
+    kind: SpawnKind, x: f64, y: f64, room_entity: Entity
+) {
+    // A spawn point that spawns nothing would only be saved to confuse the next session
+    match spawn_object(entities, lazy_update, prefabs, &kind, x, y, room_entity) {
+        Some(object) => history.create(object),
+        None => return,
+    }
+
+    let spawn_point = lazy_update.create_entity(entities)
+        .with(draw::Position { x, y })
+        .with(physics::InRoom { room_entity })
+        .with(SpawnPoint { kind })
         .marked::<U64Marker>()
         .build();
 
-    lazy_update.create_entity(entities)
-        .with(draw::Position { x: width / 2.0 - 5.0, y: height / 2.0 - 10.0 })
-        .with(draw::Shape { size: 10.0, class: draw::ShapeClass::Ball })
-        .with(physics::Velocity::default())
-        .with(physics::InRoom { room_entity: entity.id() })
+    history.create(spawn_point);
+}
+
+/// Create a room with two balls in it, and with the player's spawn point if `with_player`.
+fn create_room(
+    entities: &EntitiesRes, lazy_update: &LazyUpdate, prefabs: &Prefabs, history: &mut EditHistory,
+    x: f64, y: f64, width: f64, height: f64, with_player: bool
+) {
+    let entity = prefabs.spawn("room", entities, lazy_update)
+        .expect("Built-in prefabs always exist")
+        .with(draw::Position { x, y })
+        .with(draw::Size { width, height })
         .marked::<U64Marker>()
         .build();
 
-    if entity.id() == 0 {
-        lazy_update.create_entity(entities)
-            .with(draw::Position { x: width / 2.0, y: 20.0 })
-            .with(draw::Shape { size: 10.0, class: draw::ShapeClass::Ball })
-            .with(shift::Shifter::default())
-            .with(physics::Velocity::default())
-            .with(physics::InRoom { room_entity: entity.id() })
-            .with(input::PlayerController::default())
-            .with(control::Jump::default())
-            .with(physics::Force::default())
-            .with(physics::Aim::default())
-            .with(physics::CollisionSet::default())
-            .marked::<U64Marker>()
-            .build();
+    history.create(entity);
+
+    create_spawn_point(entities, lazy_update, prefabs, history, SpawnKind::Ball,
+                       width / 2.0 + 5.0, height / 2.0 + 10.0, entity);
+    create_spawn_point(entities, lazy_update, prefabs, history, SpawnKind::Ball,
+                       width / 2.0 - 5.0, height / 2.0 - 10.0, entity);
+
+    if with_player {
+        create_spawn_point(entities, lazy_update, prefabs, history, SpawnKind::Player,
+                           width / 2.0, 20.0, entity);
     }
 }
 
-impl <'a> System<'a> for CreateRoom {
+/// Carry out a `destruction` as part of the current edit, recording every entity it affects.
+fn destroy(destruction: saveload::Destruction, history: &mut EditHistory, lazy_update: &LazyUpdate) {
+    // Destroyed by `DestroyEntities` later in the tick, after the history saved them
+    for destroyed in destruction.destroyed {
+        history.touch(destroyed);
+        lazy_update.insert(destroyed, DestroyEntity);
+    }
+
+    // Joints and links to destroyed entities are removed right away instead of by
+    // `DestroyEntities`, so that undoing the edit restores them too
+    for detached in destruction.detached {
+        history.touch(detached);
+        lazy_update.remove::<physics::RevoluteJoint>(detached);
+        lazy_update.remove::<ChainLink>(detached);
+    }
+
+    for unlinked in destruction.unlinked {
+        history.touch(unlinked);
+        lazy_update.remove::<RoomLink>(unlinked);
+    }
+}
+
+pub struct ApplyEditEvents;
+
+impl <'a> System<'a> for ApplyEditEvents {
     type SystemData = (
         Entities<'a>,
         WriteExpect<'a, EditorController>,
+        WriteExpect<'a, EditHistory>,
+        ReadExpect<'a, Prefabs>,
+        ReadStorage<'a, physics::InRoom>,
+        ReadStorage<'a, physics::Room>,
+        ReadStorage<'a, physics::RevoluteJoint>,
+        ReadStorage<'a, draw::Shape>,
+        ReadStorage<'a, draw::Position>,
+        ReadStorage<'a, draw::Size>,
+        ReadStorage<'a, RoomLink>,
+        ReadStorage<'a, SpawnPoint>,
+        ReadStorage<'a, PlayerController>,
         ReadExpect<'a, LazyUpdate>,
     );
 
-    fn run(&mut self, (entities, mut editor_controller, lazy_update): Self::SystemData) {
+    fn run(&mut self, (entities, mut editor_controller, mut history, prefabs, in_rooms, rooms,
+        revolute_joints, shapes, positions, sizes, room_links, spawn_points, player_controllers,
+        lazy_update): Self::SystemData)
+    {
+        // Saves from before spawn points existed have a player, but no spawn point for it
+        let mut has_player_spawn = (&player_controllers).join().next().is_some() ||
+            (&spawn_points).join().any(|spawn_point| spawn_point.kind == SpawnKind::Player);
+
         while let Some(edit_event) = editor_controller.edit_events.pop_front() {
             match edit_event {
                 EditEvent::CreateRoom { x, y, width, height } => {
-                    create_room(&entities, &lazy_update, x, y, width, height);
+                    create_room(&entities, &lazy_update, &prefabs, &mut history, x, y, width, height, !has_player_spawn);
+                    has_player_spawn = true;
                 },
 
-                EditEvent::CreateTerrainBox { x, y, width, height, room_entity } => {
-                    lazy_update.create_entity(&entities)
+                EditEvent::PlaceObject { kind, x, y, room_entity } => {
+                    if rooms.get(room_entity).is_none() {
+                        continue;
+                    }
+
+                    // There is only one player, so placing it moves the player and its spawn point
+                    if kind == SpawnKind::Player {
+                        let players: Vec<Entity> = (&*entities, &spawn_points).join()
+                            .filter(|&(_entity, spawn_point)| spawn_point.kind == SpawnKind::Player)
+                            .map(|(entity, _spawn_point)| entity)
+                            .chain((&*entities, &player_controllers).join().map(|(entity, _player_controller)| entity))
+                            .collect();
+
+                        let destruction = saveload::destruction_cascade(
+                            &players, &entities, &in_rooms, &revolute_joints, &shapes, &room_links);
+
+                        destroy(destruction, &mut history, &lazy_update);
+
+                        has_player_spawn = true;
+                    }
+
+                    create_spawn_point(&entities, &lazy_update, &prefabs, &mut history, kind, x, y, room_entity);
+                },
+
+                EditEvent::CreateTerrainBox { x, y, width, height, room_entity, layer } => {
+                    let terrain = prefabs.spawn("terrain", &entities, &lazy_update)
+                        .expect("Built-in prefabs always exist")
                         .with(draw::Position { x, y })
                         .with(draw::Size { width, height })
-                        .with(physics::InRoom { room_entity: room_entity.id() })
-                        .with(animate::Animation::<animate::RoomAnimation>::new(32))
+                        .with(physics::InRoom { room_entity })
+                        .with(layer)
                         .marked::<U64Marker>()
                         .build();
+
+                    history.create(terrain);
+                },
+
+                EditEvent::Delete { entity } => {
+                    let destruction = saveload::destruction_cascade(
+                        &[entity], &entities, &in_rooms, &revolute_joints, &shapes, &room_links);
+
+                    destroy(destruction, &mut history, &lazy_update);
+                },
+
+                EditEvent::LinkRoom { room, target_room } => {
+                    if rooms.get(room).is_none() {
+                        continue;
+                    }
+
+                    history.touch(room);
+
+                    match target_room {
+                        Some(target_room) => lazy_update.insert(room, RoomLink { target_room }),
+                        None => lazy_update.remove::<RoomLink>(room),
+                    }
+                },
+
+                EditEvent::SetBounds { entity, x, y, width, height } => {
+                    let old_bounds = match world_bounds(entity, &positions, &sizes, &in_rooms) {
+                        Some(old_bounds) => old_bounds,
+                        None => continue,
+                    };
+
+                    let (offset_x, offset_y) = room_offset(entity, &positions, &in_rooms);
+
+                    history.touch(entity);
+                    lazy_update.insert(entity, draw::Position { x: x - offset_x, y: y - offset_y });
+                    lazy_update.insert(entity, draw::Size { width, height });
+
+                    // When a room is resized from its left or top edge, its contents stay where
+                    // they are in the world instead of moving along with the room's origin; those
+                    // that end up outside of the room are moved back in, as part of the same edit
+                    let resized = width != old_bounds[2] || height != old_bounds[3];
+                    let (dx, dy) = (old_bounds[0] - x, old_bounds[1] - y);
+
+                    if rooms.get(entity).is_some() && resized {
+                        for (content, in_room, position) in (&*entities, &in_rooms, &positions).join() {
+                            if in_room.room_entity != entity {
+                                continue;
+                            }
+
+                            let moved = draw::Position { x: position.x + dx, y: position.y + dy };
+                            let size = sizes.get(content);
+                            let (clamped, clamped_size) = clamp_into_room(moved, size, shapes.get(content), width, height);
+
+                            let shrunk = match (size, clamped_size) {
+                                (Some(size), Some(clamped_size)) =>
+                                    clamped_size.width != size.width || clamped_size.height != size.height,
+                                _ => false,
+                            };
+
+                            if clamped.x == position.x && clamped.y == position.y && !shrunk {
+                                continue;
+                            }
+
+                            history.touch(content);
+                            lazy_update.insert(content, clamped);
+
+                            if let (true, Some(clamped_size)) = (shrunk, clamped_size) {
+                                lazy_update.insert(content, clamped_size);
+                            }
+                        }
+                    }
                 },
             };
         }
     }
 }
+
+/// Spawn the objects of every spawn point; used when starting a new session from a level.
+pub struct SpawnObjects;
+
+impl <'a> System<'a> for SpawnObjects {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, SpawnPoint>,
+        ReadStorage<'a, draw::Position>,
+        ReadStorage<'a, physics::InRoom>,
+        ReadExpect<'a, Prefabs>,
+        ReadExpect<'a, LazyUpdate>,
+    );
+
+    fn run(&mut self, (entities, spawn_points, positions, in_rooms, prefabs, lazy_update): Self::SystemData) {
+        for (spawn_point, position, in_room) in (&spawn_points, &positions, &in_rooms).join() {
+            spawn_object(&entities, &lazy_update, &prefabs, &spawn_point.kind,
+                         position.x, position.y, in_room.room_entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Grid, clamp_into_room};
+    use draw::{Position, Size, Shape, ShapeClass};
+
+    #[test]
+    fn grids_from_files_are_clamped_to_the_supported_sizes() {
+        assert_eq!(Grid { cell_size: 0, snapping: true }.clamped().cell_size, 4);
+        assert_eq!(Grid { cell_size: -16, snapping: true }.clamped().cell_size, 4);
+        assert_eq!(Grid { cell_size: 1024, snapping: true }.clamped().cell_size, 128);
+        assert_eq!(Grid::default().clamped(), Grid::default());
+    }
+
+    #[test]
+    fn contents_of_a_shrunk_room_are_moved_back_inside() {
+        // Terrain past the new edge moves back in, and shrinks if it is wider than the room
+        let size = Size { width: 40.0, height: 20.0 };
+        let (position, size) = clamp_into_room(Position { x: 80.0, y: 10.0 }, Some(&size), None, 100.0, 50.0);
+        let size = size.unwrap();
+        assert_eq!([position.x, position.y, size.width, size.height], [60.0, 10.0, 40.0, 20.0]);
+
+        let size = Size { width: 150.0, height: 20.0 };
+        let (position, size) = clamp_into_room(Position { x: 10.0, y: 40.0 }, Some(&size), None, 100.0, 50.0);
+        let size = size.unwrap();
+        assert_eq!([position.x, position.y, size.width, size.height], [0.0, 30.0, 100.0, 20.0]);
+
+        // Objects are kept a radius away from the walls
+        let shape = Shape { size: 10.0, class: ShapeClass::Ball };
+        let (position, size) = clamp_into_room(Position { x: 120.0, y: -5.0 }, None, Some(&shape), 100.0, 50.0);
+        assert_eq!([position.x, position.y], [90.0, 10.0]);
+        assert!(size.is_none());
+
+        // Contents already inside stay where they are
+        let (position, _size) = clamp_into_room(Position { x: 50.0, y: 25.0 }, None, Some(&shape), 100.0, 50.0);
+        assert_eq!([position.x, position.y], [50.0, 25.0]);
+    }
+}
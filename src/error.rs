@@ -3,6 +3,21 @@ pub use failure::{Error, ResultExt};
 #[derive(Debug, Fail)]
 pub enum GameError {
     #[fail(display = "cannot create game window: {}", reason)]
-    WindowError { reason: String }
+    WindowError { reason: String },
+
+    #[fail(display = "invalid arguments: {}", reason)]
+    InvalidArguments { reason: String },
+
+    #[fail(display = "cannot save to '{}': {}", file_name, cause)]
+    SaveError { file_name: String, cause: String },
+
+    #[fail(display = "cannot load '{}': {}", file_name, cause)]
+    LoadError { file_name: String, cause: String },
+
+    #[fail(display = "'{}' is not a valid save: {}", file_name, cause)]
+    InvalidSave { file_name: String, cause: String },
+
+    #[fail(display = "{} refers to an entity that is not part of the save", component)]
+    DanglingReference { component: String },
 }
 
@@ -0,0 +1,109 @@
+/// Gamepad input
+///
+/// The window backend does not report controllers, so gamepads are read with gilrs and turned
+/// into the controller events piston would deliver. Buttons and axes are numbered after the SDL
+/// game controller layout, which the default bindings are written for.
+
+use gilrs::{Gilrs, Event, EventType, Axis};
+use gilrs::Button as GamepadButton;
+use piston::input::{Button, ControllerButton, ControllerAxisArgs};
+
+use input::InputEvent;
+
+/// SDL axis indices of the left and right trigger
+const LEFT_TRIGGER_AXIS: u8 = 4;
+const RIGHT_TRIGGER_AXIS: u8 = 5;
+
+pub struct Gamepads {
+    /// `None` if gamepads are not supported on this system
+    gilrs: Option<Gilrs>,
+}
+
+impl Gamepads {
+    pub fn new() -> Self {
+        let gilrs = match Gilrs::new() {
+            Ok(gilrs) => Some(gilrs),
+            Err(error) => {
+                eprintln!("Gamepads are not available: {}", error);
+                None
+            },
+        };
+
+        Gamepads { gilrs }
+    }
+
+    /// Take the input of every gamepad since the last poll.
+    pub fn poll(&mut self) -> Vec<InputEvent> {
+        let mut input_events = Vec::new();
+
+        let gilrs = match self.gilrs {
+            Some(ref mut gilrs) => gilrs,
+            None => return input_events,
+        };
+
+        while let Some(Event { id, event, .. }) = gilrs.next_event() {
+            let id: usize = id.into();
+            let id = id as i32;
+
+            let controller_button = |button| button_index(button)
+                .map(|button| Button::Controller(ControllerButton { id, button }));
+            let controller_axis = |axis, position| InputEvent::ControllerAxisEvent(
+                ControllerAxisArgs { id, axis, position });
+
+            let input_event = match event {
+                EventType::ButtonPressed(button, _) =>
+                    controller_button(button).map(InputEvent::PressEvent),
+                EventType::ButtonReleased(button, _) =>
+                    controller_button(button).map(InputEvent::ReleaseEvent),
+                // SDL reports the triggers as axes, from 0.0 to 1.0
+                EventType::ButtonChanged(GamepadButton::LeftTrigger2, value, _) =>
+                    Some(controller_axis(LEFT_TRIGGER_AXIS, value as f64)),
+                EventType::ButtonChanged(GamepadButton::RightTrigger2, value, _) =>
+                    Some(controller_axis(RIGHT_TRIGGER_AXIS, value as f64)),
+                EventType::AxisChanged(axis, value, _) => axis_index(axis)
+                    .map(|(axis, direction)| controller_axis(axis, direction * value as f64)),
+                _ => None,
+            };
+
+            input_events.extend(input_event);
+        }
+
+        input_events
+    }
+}
+
+/// The SDL index of `button`; the analog triggers are axes in SDL, not buttons.
+fn button_index(button: GamepadButton) -> Option<u8> {
+    match button {
+        GamepadButton::South => Some(0),
+        GamepadButton::East => Some(1),
+        GamepadButton::West => Some(2),
+        GamepadButton::North => Some(3),
+        GamepadButton::Select => Some(4),
+        GamepadButton::Mode => Some(5),
+        GamepadButton::Start => Some(6),
+        GamepadButton::LeftThumb => Some(7),
+        GamepadButton::RightThumb => Some(8),
+        GamepadButton::LeftTrigger => Some(9),
+        GamepadButton::RightTrigger => Some(10),
+        GamepadButton::DPadUp => Some(11),
+        GamepadButton::DPadDown => Some(12),
+        GamepadButton::DPadLeft => Some(13),
+        GamepadButton::DPadRight => Some(14),
+        _ => None,
+    }
+}
+
+/// The SDL index of `axis`, and the direction of its positive side; gilrs points the sticks' Y
+/// axes up, SDL points them down.
+fn axis_index(axis: Axis) -> Option<(u8, f64)> {
+    match axis {
+        Axis::LeftStickX => Some((0, 1.0)),
+        Axis::LeftStickY => Some((1, -1.0)),
+        Axis::RightStickX => Some((2, 1.0)),
+        Axis::RightStickY => Some((3, -1.0)),
+        Axis::LeftZ => Some((LEFT_TRIGGER_AXIS, 1.0)),
+        Axis::RightZ => Some((RIGHT_TRIGGER_AXIS, 1.0)),
+        _ => None,
+    }
+}
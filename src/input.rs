@@ -1,17 +1,28 @@
 use specs::prelude::{System, Entity, DenseVecStorage, WriteStorage, ReadStorage, ReadExpect, WriteExpect, Entities, Join};
 use std::collections::VecDeque;
-use super::{Button, Key, MouseButton};
+use super::{Button, ControllerAxisArgs};
+use piston::input::Key;
 use std::collections::HashSet;
 use std::collections::HashMap;
 use physics::Aim;
 use draw::{Position, Size, Camera, Screen};
-use physics::{InRoom, Room};
-use edit::{EditorController, EditEvent};
+use physics::{InRoom, Room, Velocity};
+use edit::{EditorController, EditEvent, EditHistory, HistoryRequest, EditorTool, DragHandle, Drag, Grid};
+use edit::{Clipboard, ClipboardRequest, world_bounds, palette};
+use prefab::Prefabs;
+use action::{Action, ActionMap, Binding, AxisDirection};
+use saveload::{SaveSlots, SlotRequest, CurrentLevel};
 
+/// How far an axis must be pushed before an action bound to it counts as held
+const AXIS_HELD_THRESHOLD: f64 = 0.5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum InputEvent {
     PressEvent(Button),
     ReleaseEvent(Button),
     MotionEvent(f64, f64),
+    ResizeEvent(f64, f64),
+    ControllerAxisEvent(ControllerAxisArgs),
 }
 
 #[derive(Default, Copy, Clone)] // FIXME: derive more
@@ -61,7 +72,7 @@ impl SelectionBox {
 
 /// Similar to `SelectionBox`, but with coordinates flipped so that width and height are always
 /// positive. Information about where the box is being dragged from is lost.
-#[derive(Debug)] // FIXME: derive more
+#[derive(Debug, Clone, Copy)] // FIXME: derive more
 pub struct SelectionRectangle {
     pub x: f64,
     pub y: f64,
@@ -70,23 +81,34 @@ pub struct SelectionRectangle {
 }
 
 impl SelectionRectangle {
-    /// Enlarge the rectangle so that all corners snap to a grid
+    /// Enlarge the rectangle so that all corners snap to a grid. Corners already on grid lines
+    /// stay where they are, but the rectangle always covers at least one cell.
     pub fn snap_to_grid(&self, cell_size: i32) -> Self {
         assert_ne!(cell_size, 0);
 
-        // Snap the top-left corner to the grid
-        let x = (self.x as i32 / cell_size * cell_size) as f64;
-        let y = (self.y as i32 / cell_size * cell_size) as f64;
+        let cell_size = cell_size as f64;
 
-        // Bring back the bottom-right corner where it originally was by compensating
-        let width = self.width + (self.x - x as f64);
-        let height = self.height + (self.y - y as f64);
+        // Snap the top-left corner down and the bottom-right corner up to the grid
+        let x = (self.x / cell_size).floor() * cell_size;
+        let y = (self.y / cell_size).floor() * cell_size;
+        let right = ((self.x + self.width) / cell_size).ceil() * cell_size;
+        let bottom = ((self.y + self.height) / cell_size).ceil() * cell_size;
 
-        // Snap the bottom-right corner to the grid
-        let width = ((width as i32 / cell_size + 1) * cell_size) as f64;
-        let height = ((height as i32 / cell_size + 1) * cell_size) as f64;
+        SelectionRectangle {
+            x,
+            y,
+            width: (right - x).max(cell_size),
+            height: (bottom - y).max(cell_size),
+        }
+    }
 
-        SelectionRectangle { x, y, width, height }
+    /// Snap the rectangle to `grid`, unless snapping is turned off.
+    pub fn snap_to(&self, grid: &Grid) -> Self {
+        if grid.snapping {
+            self.snap_to_grid(grid.cell_size)
+        } else {
+            *self
+        }
     }
 
     /// Return an array of type [f64; 4] with [x, y, width, height].
@@ -110,6 +132,14 @@ impl InputEvents {
 pub struct InputState {
     pub button_held: HashSet<Button>,
     pub button_pressed: HashMap<Button, i32>,
+    /// Presses of bound chords, as (modifier, key)
+    pub chord_pressed: HashMap<(Key, Key), i32>,
+    /// Keys pressed as part of a chord; they count as held only for the chord until released
+    pub chord_keys_held: HashSet<Key>,
+    /// Last known position of each axis, by controller id and axis index
+    pub axes: HashMap<(i32, u8), f64>,
+    /// Whether the last aim came from a stick rather than from the mouse
+    pub aiming_with_stick: bool,
     pub screen_mouse: MouseState,
     pub world_mouse: MouseState,
     // Consider adding mouse motion events
@@ -117,6 +147,8 @@ pub struct InputState {
     // Consider changing selected_region to a per-event state
     pub room_focused: Option<Entity>,
     // Maybe this is not the best resource/module for room_focused
+    /// Terrain box under the mouse, inside `room_focused`
+    pub terrain_focused: Option<Entity>,
 }
 
 impl InputState {
@@ -124,10 +156,15 @@ impl InputState {
         InputState {
             button_held: HashSet::with_capacity(16),
             button_pressed: HashMap::with_capacity(16),
+            chord_pressed: HashMap::with_capacity(4),
+            chord_keys_held: HashSet::with_capacity(4),
+            axes: HashMap::with_capacity(8),
+            aiming_with_stick: false,
             screen_mouse: MouseState::default(),
             world_mouse: MouseState::default(),
             selected_world_region: None,
             room_focused: None,
+            terrain_focused: None,
         }
     }
 
@@ -144,6 +181,98 @@ impl InputState {
     pub fn button_pressed(&mut self, button: &Button) -> bool {
         self.button_pressed.remove(&button).is_some()
     }
+
+    /// How far `axis` is pushed in `direction` on any controller, from 0.0 to 1.0, ignoring the
+    /// dead zone around the center.
+    pub fn axis_amount(&self, axis: u8, direction: AxisDirection) -> f64 {
+        self.axes.iter()
+            .filter(|&(&(_controller, pushed_axis), _)| pushed_axis == axis)
+            .map(|(_, &position)| direction.amount(position))
+            .fold(0.0, f64::max)
+    }
+
+    /// The held modifier that turns a press of `key` into a bound chord, if any.
+    fn chord_modifier(&self, action_map: &ActionMap, key: Key) -> Option<Key> {
+        action_map.chord_modifier(&self.button_held, key)
+    }
+
+    /// Consume the presses of every button matching `binding`, and return whether there were any.
+    fn pop_binding_presses(&mut self, binding: &Binding) -> bool {
+        if let Binding::Chord(modifier, key) = *binding {
+            return self.chord_pressed.remove(&(modifier, key)).is_some();
+        }
+
+        let pressed: Vec<Button> = self.button_pressed.keys()
+            .filter(|button| binding.matches(button))
+            .cloned()
+            .collect();
+
+        for button in &pressed {
+            self.button_pressed.remove(button);
+        }
+
+        !pressed.is_empty()
+    }
+
+    fn binding_pressed_or_held(&mut self, binding: &Binding) -> bool {
+        match *binding {
+            Binding::Axis(axis, direction) =>
+                self.axis_amount(axis, direction) >= AXIS_HELD_THRESHOLD,
+            Binding::Chord(modifier, key) => {
+                let pressed = self.pop_binding_presses(binding);
+                pressed || (self.button_held.contains(&Button::Keyboard(modifier)) &&
+                    self.chord_keys_held.contains(&key))
+            },
+            _ => {
+                let pressed = self.pop_binding_presses(binding);
+                pressed || self.button_held.iter().any(|button| binding.matches(button))
+            },
+        }
+    }
+
+    /// Like `button_pressed_or_held`, for any of the bindings of `action`. Presses of all of them
+    /// are consumed.
+    pub fn action_pressed_or_held(&mut self, action_map: &ActionMap, action: Action) -> bool {
+        let mut active = false;
+
+        for binding in action_map.bindings(action) {
+            active |= self.binding_pressed_or_held(binding);
+        }
+
+        active
+    }
+
+    /// Axis bindings are never considered pressed, only held.
+    pub fn action_pressed(&mut self, action_map: &ActionMap, action: Action) -> bool {
+        let mut pressed = false;
+
+        for binding in action_map.bindings(action) {
+            if let Binding::Axis(..) = *binding {
+                continue;
+            }
+
+            pressed |= self.pop_binding_presses(binding);
+        }
+
+        pressed
+    }
+
+    /// How strongly `action` is active, from 0.0 to 1.0; buttons are either fully on or off.
+    pub fn action_value(&mut self, action_map: &ActionMap, action: Action) -> f64 {
+        let mut value: f64 = 0.0;
+
+        for binding in action_map.bindings(action) {
+            let binding_value = match *binding {
+                Binding::Axis(axis, direction) => self.axis_amount(axis, direction),
+                _ if self.binding_pressed_or_held(binding) => 1.0,
+                _ => 0.0,
+            };
+
+            value = value.max(binding_value);
+        }
+
+        value
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -176,18 +305,30 @@ impl <'a> System<'a> for InputEventsToState {
         WriteExpect<'a, InputEvents>,
         ReadExpect<'a, Camera>,
         WriteExpect<'a, InputState>,
+        WriteExpect<'a, Screen>,
+        ReadExpect<'a, ActionMap>,
     );
 
-    fn run(&mut self, (mut input_events, camera, mut input_state): Self::SystemData) {
+    fn run(&mut self, (mut input_events, camera, mut input_state, mut screen, action_map): Self::SystemData) {
         input_state.button_pressed.clear();
+        input_state.chord_pressed.clear();
         input_state.selected_world_region = None;
 
         while let Some(input_event) = input_events.events.pop_front() {
             match input_event {
+                InputEvent::PressEvent(Button::Keyboard(key))
+                    if input_state.chord_modifier(&action_map, key).is_some() =>
+                {
+                    let modifier = input_state.chord_modifier(&action_map, key)
+                        .expect("Checked by the match guard");
+
+                    input_state.chord_keys_held.insert(key);
+                    *input_state.chord_pressed.entry((modifier, key)).or_insert(0) += 1;
+                },
                 InputEvent::PressEvent(button) => {
                     input_state.button_held.insert(button);
 
-                    if let Button::Mouse(MouseButton::Left) = button {
+                    if action_map.is_bound(Action::Select, &button) {
                         input_state.screen_mouse.dragging_from = Some(input_state.screen_mouse.position);
                         input_state.world_mouse.dragging_from = Some(input_state.world_mouse.position);
                     }
@@ -200,7 +341,11 @@ impl <'a> System<'a> for InputEventsToState {
                 InputEvent::ReleaseEvent(button) => {
                     input_state.button_held.remove(&button);
 
-                    if let Button::Mouse(MouseButton::Left) = button {
+                    if let Button::Keyboard(key) = button {
+                        input_state.chord_keys_held.remove(&key);
+                    }
+
+                    if action_map.is_bound(Action::Select, &button) {
                         input_state.selected_world_region = input_state.world_mouse.selection_box();
                         input_state.screen_mouse.dragging_from = None;
                         input_state.world_mouse.dragging_from = None;
@@ -209,6 +354,14 @@ impl <'a> System<'a> for InputEventsToState {
                 InputEvent::MotionEvent(x, y) => {
                     input_state.screen_mouse.position = (x, y);
                     input_state.world_mouse.position = (x + camera.x, y + camera.y);
+                    input_state.aiming_with_stick = false;
+                },
+                InputEvent::ResizeEvent(width, height) => {
+                    screen.width = width;
+                    screen.height = height;
+                },
+                InputEvent::ControllerAxisEvent(axis_args) => {
+                    input_state.axes.insert((axis_args.id, axis_args.axis), axis_args.position);
                 },
             };
         }
@@ -222,15 +375,14 @@ impl <'a> System<'a> for PlayerControllerInput {
         Entities<'a>,
         WriteStorage<'a, PlayerController>,
         WriteExpect<'a, InputState>,
+        ReadExpect<'a, ActionMap>,
     );
 
-    fn run(&mut self, (entities, mut player_controllers, mut input_state): Self::SystemData) {
-        let moving_left = input_state.button_pressed_or_held(&Button::Keyboard(Key::Left)) ||
-            input_state.button_pressed_or_held(&Button::Keyboard(Key::A));
-        let moving_right = input_state.button_pressed_or_held(&Button::Keyboard(Key::Right)) ||
-            input_state.button_pressed_or_held(&Button::Keyboard(Key::D));
-        let jumping = input_state.button_pressed_or_held(&Button::Keyboard(Key::Space));
-        let shifting = input_state.button_pressed_or_held(&Button::Keyboard(Key::Z));
+    fn run(&mut self, (entities, mut player_controllers, mut input_state, action_map): Self::SystemData) {
+        let moving_left = input_state.action_pressed_or_held(&action_map, Action::MoveLeft);
+        let moving_right = input_state.action_pressed_or_held(&action_map, Action::MoveRight);
+        let jumping = input_state.action_pressed_or_held(&action_map, Action::Jump);
+        let shifting = input_state.action_pressed_or_held(&action_map, Action::Shift);
 
         let movement = match (moving_left, moving_right) {
             (true, false) => Movement::Left,
@@ -239,7 +391,7 @@ impl <'a> System<'a> for PlayerControllerInput {
             (false, false) => Movement::None,
         };
 
-        let hooking = input_state.button_pressed_or_held(&Button::Mouse(MouseButton::Right));
+        let hooking = input_state.action_pressed_or_held(&action_map, Action::Hook);
 
         for (_entity, mut player_controller) in (&*entities, &mut player_controllers).join() {
             player_controller.moving = movement;
@@ -259,11 +411,14 @@ impl <'a> System<'a> for MouseInsideRoom {
         ReadStorage<'a, Position>,
         ReadStorage<'a, Size>,
         ReadStorage<'a, Room>,
+        ReadStorage<'a, InRoom>,
+        ReadStorage<'a, Velocity>,
         WriteExpect<'a, InputState>,
     );
 
-    fn run(&mut self, (entities, positions, sizes, rooms, mut input_state): Self::SystemData) {
+    fn run(&mut self, (entities, positions, sizes, rooms, in_rooms, velocities, mut input_state): Self::SystemData) {
         input_state.room_focused = None;
+        input_state.terrain_focused = None;
 
         for (entity, position, size, _room) in (&*entities, &positions, &sizes, &rooms).join() {
             // Get the position relative to the room
@@ -275,6 +430,25 @@ impl <'a> System<'a> for MouseInsideRoom {
                 input_state.room_focused = Some(entity);
             }
         }
+
+        let (room_entity, room_position) = match input_state.room_focused {
+            Some(room_entity) => match positions.get(room_entity) {
+                Some(room_position) => (room_entity, *room_position),
+                None => return,
+            },
+            None => return,
+        };
+
+        let x = input_state.world_mouse.position.0 - room_position.x;
+        let y = input_state.world_mouse.position.1 - room_position.y;
+
+        for (entity, position, size, in_room, ()) in (&*entities, &positions, &sizes, &in_rooms, !&velocities).join() {
+            if in_room.room_entity == room_entity && x >= position.x && y >= position.y &&
+                x < position.x + size.width && y < position.y + size.height
+            {
+                input_state.terrain_focused = Some(entity);
+            }
+        }
     }
 }
 
@@ -283,20 +457,68 @@ pub struct EditorControllerInput;
 impl <'a> System<'a> for EditorControllerInput {
     type SystemData = (
         WriteExpect<'a, EditorController>,
+        WriteExpect<'a, EditHistory>,
         WriteExpect<'a, Camera>,
         WriteExpect<'a, InputState>,
+        ReadExpect<'a, ActionMap>,
+        WriteExpect<'a, Grid>,
+        WriteExpect<'a, CurrentLevel>,
+        ReadExpect<'a, Prefabs>,
+        WriteExpect<'a, Clipboard>,
         ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        ReadStorage<'a, InRoom>,
     );
 
-    fn run(&mut self, (mut editor_controller, mut camera, mut input_state, positions): Self::SystemData) {
-        // FIXME: Loop over a mouse motion event queue instead, to handle cases where multiple
-        // boxes are drawn in a single update (e.g. during lag or testing code)
-        if let Some(ref selection_box) = input_state.selected_world_region {
+    fn run(&mut self, (mut editor_controller, mut history, mut camera, mut input_state, action_map,
+        mut grid, mut current_level, prefabs, mut clipboard, positions, sizes, in_rooms): Self::SystemData)
+    {
+        let new_grid = if input_state.action_pressed(&action_map, Action::GridSmaller) {
+            grid.smaller()
+        } else if input_state.action_pressed(&action_map, Action::GridLarger) {
+            grid.larger()
+        } else if input_state.action_pressed(&action_map, Action::ToggleSnapping) {
+            Grid { snapping: !grid.snapping, .. *grid }
+        } else {
+            *grid
+        };
+
+        if new_grid != *grid {
+            // The grid is saved with the level, but changing it is not an undoable edit
+            *grid = new_grid;
+            current_level.modified = true;
+            println!("Editor grid: {} pixels, snapping {}", grid.cell_size, if grid.snapping { "on" } else { "off" });
+        }
+
+        if input_state.action_pressed(&action_map, Action::NextTool) {
+            editor_controller.tool = editor_controller.tool.next();
+            editor_controller.selected = None;
+            editor_controller.drag = None;
+            editor_controller.linking_from = None;
+            println!("Editor tool: {:?}", editor_controller.tool);
+        }
+
+        if input_state.action_pressed(&action_map, Action::NextLayer) {
+            editor_controller.layer = editor_controller.layer.next();
+            println!("Drawing terrain on layer: {:?}", editor_controller.layer);
+        }
+
+        if editor_controller.tool == EditorTool::Select {
+            select_tool_input(&mut editor_controller, &mut input_state, &action_map, &grid, &positions, &sizes, &in_rooms);
+        } else if editor_controller.tool == EditorTool::Link {
+            link_tool_input(&mut editor_controller, &mut input_state, &action_map);
+        } else if editor_controller.tool == EditorTool::Place {
+            place_tool_input(&mut editor_controller, &mut input_state, &action_map, &prefabs, &positions);
+        } else if let Some(ref selection_box) = input_state.selected_world_region {
+            // FIXME: Loop over a mouse motion event queue instead, to handle cases where multiple
+            // boxes are drawn in a single update (e.g. during lag or testing code)
             let rectangle = selection_box
                 .to_rectangle()
-                .snap_to_grid(16);
+                .snap_to(&grid);
 
-            if let Some(room_entity) = input_state.room_focused {
+            if rectangle.width < grid.min_size() || rectangle.height < grid.min_size() {
+                // A click without dragging, with snapping turned off
+            } else if let Some(room_entity) = input_state.room_focused {
                 if let Some(Position { x, y }) = positions.get(room_entity) {
                     // Turn x and y into room-relative positions
                     editor_controller.push_event(EditEvent::CreateTerrainBox {
@@ -305,6 +527,7 @@ impl <'a> System<'a> for EditorControllerInput {
                         width: rectangle.width,
                         height: rectangle.height,
                         room_entity,
+                        layer: editor_controller.layer,
                     });
                 }
             } else {
@@ -317,32 +540,204 @@ impl <'a> System<'a> for EditorControllerInput {
             }
         };
 
+        if input_state.action_pressed(&action_map, Action::Delete) {
+            let target = editor_controller.selected.take()
+                .or(input_state.terrain_focused)
+                .or(input_state.room_focused);
+
+            if let Some(entity) = target {
+                editor_controller.push_event(EditEvent::Delete { entity });
+            }
+        }
+
+        if input_state.action_pressed(&action_map, Action::Copy) {
+            clipboard.request = input_state.room_focused.map(ClipboardRequest::Copy);
+        } else if input_state.action_pressed(&action_map, Action::Duplicate) {
+            clipboard.request = input_state.room_focused.map(ClipboardRequest::Duplicate);
+        } else if input_state.action_pressed(&action_map, Action::Paste) {
+            let (x, y) = input_state.world_mouse.position;
+            clipboard.request = Some(ClipboardRequest::Paste(grid.snap(x), grid.snap(y)));
+        }
+
+        if input_state.action_pressed(&action_map, Action::Undo) {
+            history.request = Some(HistoryRequest::Undo);
+        } else if input_state.action_pressed(&action_map, Action::Redo) {
+            history.request = Some(HistoryRequest::Redo);
+        }
+
         // FIXME: Maybe move this to its own Camera-specific place?
-        if input_state.button_pressed(&Button::Keyboard(Key::C)) {
+        if input_state.action_pressed(&action_map, Action::ToggleCamera) {
             camera.mode = camera.mode.next_mode();
         }
     }
 }
 
+/// Pick a room or terrain box by pressing on it, and move or resize it by dragging its inside or
+/// its edges.
+fn select_tool_input(
+    editor_controller: &mut EditorController, input_state: &mut InputState, action_map: &ActionMap,
+    grid: &Grid, positions: &ReadStorage<Position>, sizes: &ReadStorage<Size>, in_rooms: &ReadStorage<InRoom>
+) {
+    let (mouse_x, mouse_y) = input_state.world_mouse.position;
+
+    if input_state.action_pressed(action_map, Action::Select) {
+        let selected_bounds = editor_controller.selected
+            .and_then(|selected| world_bounds(selected, positions, sizes, in_rooms));
+
+        // The handles of the current selection take precedence over whatever is under them
+        let grabbed = selected_bounds
+            .and_then(|bounds| DragHandle::at(bounds, mouse_x, mouse_y))
+            .map(|handle| (editor_controller.selected, handle));
+
+        let (selected, handle) = grabbed.unwrap_or_else(|| {
+            let move_handle = DragHandle { left: true, right: true, top: true, bottom: true };
+            (input_state.terrain_focused.or(input_state.room_focused), move_handle)
+        });
+
+        editor_controller.selected = selected;
+        editor_controller.drag = selected.and_then(|entity| {
+            world_bounds(entity, positions, sizes, in_rooms).map(|bounds| Drag {
+                entity,
+                handle,
+                bounds,
+                from: (mouse_x, mouse_y),
+            })
+        });
+    }
+
+    if input_state.selected_world_region.is_some() {
+        if let Some(drag) = editor_controller.drag.take() {
+            let [x, y, width, height] = drag.bounds_at((mouse_x, mouse_y), grid);
+
+            if [x, y, width, height] != drag.bounds {
+                editor_controller.push_event(EditEvent::SetBounds { entity: drag.entity, x, y, width, height });
+            }
+        }
+    }
+}
+
+/// Drag from a room to another to make objects in the first one shift to the second; dragging
+/// from a room to itself or to empty space removes its link.
+fn link_tool_input(editor_controller: &mut EditorController, input_state: &mut InputState, action_map: &ActionMap) {
+    if input_state.action_pressed(action_map, Action::Select) {
+        editor_controller.linking_from = input_state.room_focused;
+    }
+
+    if input_state.selected_world_region.is_some() {
+        if let Some(room) = editor_controller.linking_from.take() {
+            let target_room = input_state.room_focused
+                .filter(|&target_room| target_room != room);
+
+            editor_controller.push_event(EditEvent::LinkRoom { room, target_room });
+        }
+    }
+}
+
+/// Click inside a room to place the selected object of the palette there, and cycle through the
+/// palette with `NextPaletteItem`.
+fn place_tool_input(
+    editor_controller: &mut EditorController, input_state: &mut InputState, action_map: &ActionMap,
+    prefabs: &Prefabs, positions: &ReadStorage<Position>
+) {
+    let palette = palette(prefabs);
+    let next_item = input_state.action_pressed(action_map, Action::NextPaletteItem);
+
+    if next_item {
+        editor_controller.palette_index = (editor_controller.palette_index + 1) % palette.len();
+    }
+
+    // The palette shrinks when prefabs are reloaded without some of the objects
+    let kind = match palette.get(editor_controller.palette_index) {
+        Some(kind) => kind.clone(),
+        None => {
+            editor_controller.palette_index = 0;
+            palette[0].clone()
+        },
+    };
+
+    if next_item {
+        println!("Placing: {}", kind.prefab_name());
+    }
+
+    if input_state.action_pressed(action_map, Action::Select) {
+        let (mouse_x, mouse_y) = input_state.world_mouse.position;
+
+        if let Some(room_entity) = input_state.room_focused {
+            if let Some(Position { x, y }) = positions.get(room_entity) {
+                editor_controller.push_event(EditEvent::PlaceObject {
+                    kind,
+                    x: mouse_x - x,
+                    y: mouse_y - y,
+                    room_entity,
+                });
+            }
+        }
+    }
+}
+
+pub struct SaveSlotInput;
+
+impl <'a> System<'a> for SaveSlotInput {
+    type SystemData = (
+        WriteExpect<'a, SaveSlots>,
+        WriteExpect<'a, InputState>,
+        ReadExpect<'a, ActionMap>,
+    );
+
+    fn run(&mut self, (mut save_slots, mut input_state, action_map): Self::SystemData) {
+        for slot in 1..10 {
+            if input_state.action_pressed(&action_map, Action::SelectSaveSlot(slot)) {
+                save_slots.current = slot;
+                println!("Selected save slot {}", slot);
+            }
+        }
+
+        if input_state.action_pressed(&action_map, Action::QuickSave) {
+            save_slots.request = Some(SlotRequest::Save(save_slots.current));
+        } else if input_state.action_pressed(&action_map, Action::QuickLoad) {
+            save_slots.request = Some(SlotRequest::Load(save_slots.current));
+        }
+    }
+}
+
 pub struct AimObjects;
 
 impl <'a> System<'a> for AimObjects {
     type SystemData = (
         Entities<'a>,
         WriteExpect<'a, InputState>,
+        ReadExpect<'a, ActionMap>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, InRoom>,
         WriteStorage<'a, Aim>,
     );
 
-    fn run(&mut self, (entities, mut input_state, positions, in_rooms, mut aims): Self::SystemData) {
+    fn run(&mut self, (entities, mut input_state, action_map, positions, in_rooms, mut aims): Self::SystemData) {
+        let stick_aim = (
+            input_state.action_value(&action_map, Action::AimRight)
+                - input_state.action_value(&action_map, Action::AimLeft),
+            input_state.action_value(&action_map, Action::AimDown)
+                - input_state.action_value(&action_map, Action::AimUp),
+        );
+
+        // The stick keeps its last direction when released, until the mouse moves again
+        if stick_aim != (0.0, 0.0) {
+            input_state.aiming_with_stick = true;
+        }
+
         for (_entity, position, in_room, mut aim) in (&*entities, &positions, &in_rooms, &mut aims).join() {
             // FIXME: Find a better control scheme than holding down Ctrl; but not always-on
-            let _aim_button = input_state.button_pressed_or_held(&Button::Keyboard(Key::LCtrl));
+            let _aim_button = input_state.action_pressed_or_held(&action_map, Action::Aim);
             let aim_button = true;
 
-            if aim_button {
-                let room_entity = entities.entity(in_room.room_entity);
+            if aim_button && input_state.aiming_with_stick {
+                aim.aiming = true;
+
+                if stick_aim != (0.0, 0.0) {
+                    aim.aiming_toward = stick_aim;
+                }
+            } else if aim_button {
+                let room_entity = in_room.room_entity;
 
                 let room_position = match positions.get(room_entity) {
                     Some(room_position) => room_position,
@@ -373,6 +768,10 @@ impl <'a> System<'a> for GlobalInput {
         for (button, press_count) in input_state.button_pressed.drain() {
             println!("Unhandled key {:?} pressed {} times", button, press_count);
         }
+
+        for ((modifier, key), press_count) in input_state.chord_pressed.drain() {
+            println!("Unhandled key {:?}+{:?} pressed {} times", modifier, key, press_count);
+        }
     }
 }
 
@@ -381,12 +780,13 @@ pub struct CameraEdgePan;
 impl <'a> System<'a> for CameraEdgePan {
     type SystemData = (
         WriteExpect<'a, InputState>,
+        ReadExpect<'a, ActionMap>,
         ReadExpect<'a, Screen>,
         WriteExpect<'a, Camera>,
     );
 
-    fn run(&mut self, (mut input_state, screen, mut camera): Self::SystemData) {
-        let pan_button = input_state.button_pressed_or_held(&Button::Mouse(MouseButton::Middle));
+    fn run(&mut self, (mut input_state, action_map, screen, mut camera): Self::SystemData) {
+        let pan_button = input_state.action_pressed_or_held(&action_map, Action::Pan);
 
         if pan_button || input_state.screen_mouse.dragging_from.is_some() {
             let horizontal_direction = match input_state.screen_mouse.position.0 {
@@ -407,3 +807,33 @@ impl <'a> System<'a> for CameraEdgePan {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SelectionRectangle;
+
+    fn rectangle(x: f64, y: f64, width: f64, height: f64) -> SelectionRectangle {
+        SelectionRectangle { x, y, width, height }
+    }
+
+    #[test]
+    fn snap_to_grid_keeps_rectangles_already_on_the_grid() {
+        let snapped = rectangle(16.0, 32.0, 48.0, 16.0).snap_to_grid(16);
+
+        assert_eq!(snapped.to_array(), [16.0, 32.0, 48.0, 16.0]);
+    }
+
+    #[test]
+    fn snap_to_grid_enlarges_rectangles_to_the_cells_they_touch() {
+        let snapped = rectangle(5.0, 20.0, 20.0, 10.0).snap_to_grid(16);
+
+        assert_eq!(snapped.to_array(), [0.0, 16.0, 32.0, 16.0]);
+    }
+
+    #[test]
+    fn snap_to_grid_covers_at_least_one_cell() {
+        let snapped = rectangle(32.0, 32.0, 0.0, 0.0).snap_to_grid(16);
+
+        assert_eq!(snapped.to_array(), [32.0, 32.0, 16.0, 16.0]);
+    }
+}
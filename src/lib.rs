@@ -6,6 +6,9 @@
 /// draw.rs:
 ///   - `ClearScreen`
 ///   - `DrawRooms`
+///   - `DrawGrid`
+///   - `DrawTerrain`
+///   - `DrawRoomLinks`
 ///   - `DrawSelectionBox`
 /// animate.rs:
 ///   - `UpdateAnimations`
@@ -20,6 +23,7 @@
 /// animate.rs:
 ///   - `Animation<T>`
 /// physics.rs:
+///   - `RoomProperties`
 ///   - `PhysicalObject`
 ///   - `PhysicalRoom`?
 /// saveload.rs:
@@ -40,6 +44,7 @@ extern crate opengl_graphics;
 extern crate piston;
 extern crate graphics;
 extern crate glutin_window;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
@@ -50,6 +55,7 @@ extern crate ron;
 extern crate nalgebra;
 extern crate nphysics2d;
 extern crate ncollide2d;
+extern crate gilrs;
 extern crate core;
 
 
@@ -57,34 +63,44 @@ use opengl_graphics::{GlGraphics, OpenGL};
 use glutin_window::GlutinWindow;
 use piston::input::{UpdateEvent, UpdateArgs};
 use piston::input::{RenderEvent, RenderArgs};
-use piston::input::{PressEvent, ReleaseEvent, Key, Button, MouseButton};
-use piston::input::{MouseCursorEvent};
+use piston::input::{PressEvent, ReleaseEvent, Button};
+use piston::input::{MouseCursorEvent, ResizeEvent};
+use piston::input::ControllerAxisArgs;
 use piston::window::{WindowSettings, Window};
 use piston::event_loop::{Events, EventSettings};
-use specs::prelude::{World, RunNow};
-use specs::saveload::U64Marker;
-use specs::saveload::U64MarkerAllocator;
 
 mod draw;
 mod input;
+mod action;
 mod control;
 mod shift;
 mod edit;
 mod animate;
 mod physics;
 mod saveload;
+mod saveformat;
 mod error;
-
-use error::{GameError, Error};
+mod simulation;
+mod replay;
+mod options;
+mod prefab;
+mod validate;
+mod gamepad;
+
+use error::{GameError, Error, ResultExt};
 use draw::run_draw_systems;
-use physics::PhysicsSystem;
-use input::{InputEvents, InputEvent};
+use gamepad::Gamepads;
+pub use input::InputEvent;
+pub use action::{Action, ActionMap};
+pub use simulation::{Simulation, Autosave, TICK_DURATION};
+pub use options::{Options, USAGE};
+pub use validate::validate_level;
+pub use prefab::{Prefab, Prefabs};
 
 
 struct Game {
     gl: GlGraphics,
-    specs_world: World,
-    physics_system: PhysicsSystem,
+    simulation: Simulation,
 }
 
 pub struct UpdateDeltaTime {
@@ -93,74 +109,31 @@ pub struct UpdateDeltaTime {
 
 impl Game {
     fn render(&mut self, args: &RenderArgs) {
-        run_draw_systems(&mut self.specs_world, &mut self.gl, *args);
+        run_draw_systems(self.simulation.specs_world_mut(), &mut self.gl, *args);
     }
 
     fn update(&mut self, args: &UpdateArgs) {
-        let () = {
-            let mut update_delta_time = self.specs_world.write_resource::<UpdateDeltaTime>();
-            update_delta_time.dt = args.dt;
-        };
-
-        input::InputEventsToState.run_now(&mut self.specs_world.res);
-        input::MouseInsideRoom.run_now(&mut self.specs_world.res);
-        input::PlayerControllerInput.run_now(&mut self.specs_world.res);
-        input::EditorControllerInput.run_now(&mut self.specs_world.res);
-        input::AimObjects.run_now(&mut self.specs_world.res);
-        input::GlobalInput.run_now(&mut self.specs_world.res);
-        input::CameraEdgePan.run_now(&mut self.specs_world.res);
-
-        shift::TrackShiftTarget.run_now(&mut self.specs_world.res);
-        control::ControlObjects.run_now(&mut self.specs_world.res);
-        edit::CreateRoom.run_now(&mut self.specs_world.res);
-        shift::PhaseShift.run_now(&mut self.specs_world.res);
-
-        self.specs_world.maintain();
-        self.physics_system.run_now(&mut self.specs_world.res);
-
-        animate::UpdateAnimations.run_now(&mut self.specs_world.res);
-        control::UpdateCooldowns.run_now(&mut self.specs_world.res);
-        control::FireHook.run_now(&mut self.specs_world.res);
-        shift::StartPhaseShift.run_now(&mut self.specs_world.res);
-
-        // Must be left at the end in order to allow every other system to react on destroyed
-        // entities.
-        // FIXME: Obsolete, remove the component and system
-        saveload::DestroyEntities.run_now(&mut self.specs_world.res);
-        self.specs_world.maintain();
+        self.simulation.update(args.dt);
     }
 
     fn press(&mut self, args: &Button) {
-        self.specs_world.write_resource::<InputEvents>().events
-            .push_back(InputEvent::PressEvent(*args));
-
-        // FIXME: Move to edit.rs
-        if let &Button::Keyboard(Key::R) = args {
-            saveload::ResetWorld.run_now(&mut self.specs_world.res);
-            self.specs_world.maintain();
-        }
+        self.simulation.push_input_event(InputEvent::PressEvent(*args));
     }
 
     fn release(&mut self, args: &Button) {
-        self.specs_world.write_resource::<InputEvents>().events
-            .push_back(InputEvent::ReleaseEvent(*args));
+        self.simulation.push_input_event(InputEvent::ReleaseEvent(*args));
     }
 
     fn mouse_cursor(&mut self, x: f64, y: f64) {
-        self.specs_world.write_resource::<InputEvents>().events
-            .push_back(InputEvent::MotionEvent(x, y));
+        self.simulation.push_input_event(InputEvent::MotionEvent(x, y));
     }
-}
-
-pub fn run() -> Result<(), Error> {
-    let opengl_version = OpenGL::V3_2;
 
-    let mut window: GlutinWindow = WindowSettings::new("stacked-worlds", [640, 480])
-        .opengl(opengl_version)
-        .exit_on_esc(true)
-        .build()
-        .map_err(|err| GameError::WindowError { reason: err })?;
+    fn resize(&mut self, width: f64, height: f64) {
+        self.simulation.push_input_event(InputEvent::ResizeEvent(width, height));
+    }
+}
 
+pub fn run(options: Options) -> Result<(), Error> {
 //    let game_state = {
 //        let state_file = std::fs::File::open("state.json");
 //
@@ -177,48 +150,110 @@ pub fn run() -> Result<(), Error> {
 //        }
 //    };
 
-    let mut world = World::new();
-
-    world.register::<saveload::DestroyEntity>();
-    world.register::<input::PlayerController>();
-    world.register::<control::Jump>();
-    world.register::<control::ChainLink>();
-    world.register::<draw::Position>();
-    world.register::<draw::Size>();
-    world.register::<draw::Shape>();
-    world.register::<shift::Shifter>();
-    world.register::<animate::Animation<animate::RoomAnimation>>();
-    world.register::<physics::Velocity>();
-    world.register::<physics::Force>();
-    world.register::<physics::Aim>();
-    world.register::<physics::CollisionSet>();
-    world.register::<physics::RevoluteJoint>();
-    world.register::<physics::Room>();
-    world.register::<physics::InRoom>();
-    world.register::<U64Marker>();
-
-    world.add_resource(U64MarkerAllocator::new());
-    world.add_resource(UpdateDeltaTime { dt: 0.0 });
-    world.add_resource(input::InputEvents::new());
-    world.add_resource(input::InputState::new());
-    world.add_resource(edit::EditorController::new());
-    world.add_resource(draw::Camera::new());
-    world.add_resource(draw::Screen { width: window.draw_size().width as f64, height: window.draw_size().height as f64 });
+    let mut simulation = Simulation::new();
+    simulation.set_action_map(ActionMap::load(&options.bindings_file)?);
+    simulation.set_prefabs(Prefabs::load(&options.prefabs_file)?);
+
+    if let Some(ref replay_file) = options.replay {
+        simulation.start_replay(replay_file)?;
+    } else if let Some(backup) = options.restore_backup {
+        let backup_file = saveload::backup_file_name(&options.save_file, backup);
+        println!("Restoring the world from '{}'.", backup_file);
+
+        simulation.load(&backup_file, &backup_file)?;
+    } else if let Some(ref level_file) = options.level {
+        println!("Starting a new session in level '{}'.", level_file);
+
+        simulation.start_level(level_file)?;
+    } else if let Err(error) = simulation.load(&options.save_file, &options.default_storage) {
+        // Only a save that is itself broken is replaced; one that cannot be read right now, or
+        // whose level is missing, is left alone for the player to sort out
+        let invalid = match error.downcast_ref::<GameError>() {
+            Some(&GameError::InvalidSave { ref file_name, .. }) => *file_name == options.save_file,
+            _ => false,
+        };
+
+        if !invalid {
+            return Err(error);
+        }
+
+        eprintln!("Error: {}; starting from '{}'.", error, options.default_storage);
+
+        // Keep the unreadable save around instead of overwriting it on exit
+        let corrupt_file = format!("{}.corrupt", options.save_file);
+        eprintln!("Moving '{}' to '{}'.", options.save_file, corrupt_file);
+
+        std::fs::rename(&options.save_file, &corrupt_file)
+            .context(format!("Cannot move '{}' out of the way", options.save_file))?;
+
+        simulation.load(&options.default_storage, &options.default_storage)?;
+    }
+
+    if let Some(ref record_file) = options.record {
+        simulation.start_recording(record_file)?;
+    }
+
+    if !options.no_save && options.replay.is_none() && options.autosave_seconds > 0.0 {
+        simulation.set_autosave(Some(Autosave {
+            file_name: options.save_file.clone(),
+            interval: (options.autosave_seconds / TICK_DURATION).round().max(1.0) as u64,
+            backups: options.backups,
+        }));
+    }
+
+    let mut simulation = if let Some(ticks) = options.headless_ticks {
+        let (width, height) = options.window_size;
+        simulation.push_input_event(InputEvent::ResizeEvent(width as f64, height as f64));
+
+        for _ in 0..ticks {
+            simulation.tick();
+        }
+
+        simulation
+    } else {
+        run_window(&options, simulation)?
+    };
+
+    simulation.stop_recording()?;
+
+    if !options.no_save {
+        simulation.save(&options.save_file, options.backups)?;
+    }
+
+//    let state_file = std::fs::File::create("state.json")
+//        .context("Cannot create file to save game state")?;
+//    serde_json::to_writer(state_file, &game.state)
+//        .context("Cannot write game state to file")?;
+
+    Ok(())
+}
+
+fn run_window(options: &Options, simulation: Simulation) -> Result<Simulation, Error> {
+    let opengl_version = OpenGL::V3_2;
+    let (width, height) = options.window_size;
+
+    let mut window: GlutinWindow = WindowSettings::new("stacked-worlds", [width, height])
+        .opengl(opengl_version)
+        .fullscreen(options.fullscreen)
+        .exit_on_esc(true)
+        .build()
+        .map_err(|err| GameError::WindowError { reason: err })?;
 
     let mut game = Game {
         gl: GlGraphics::new(opengl_version),
-        physics_system: PhysicsSystem::new(),
-        specs_world: world,
+        simulation,
     };
 
-    saveload::LoadWorld {
-        file_name: "storage.ron".into(),
-        default_storage: "default-storage.ron".into(),
-    }.run_now(&mut game.specs_world.res);
+    game.resize(window.draw_size().width as f64, window.draw_size().height as f64);
 
+    let mut gamepads = Gamepads::new();
     let mut events = Events::new(EventSettings::new());
 
     while let Some(event) = events.next(&mut window) {
+        for input_event in gamepads.poll() {
+            game.simulation.push_input_event(input_event);
+        }
+
         if let Some(render_args) = event.render_args() {
             game.render(&render_args);
         }
@@ -238,15 +273,11 @@ pub fn run() -> Result<(), Error> {
         if let Some(mouse_cursor_args) = event.mouse_cursor_args() {
             game.mouse_cursor(mouse_cursor_args[0], mouse_cursor_args[1]);
         }
-    }
-
-    game.specs_world.maintain();
-    saveload::SaveWorld { file_name: "storage.ron".into() }.run_now(&game.specs_world.res);
 
-//    let state_file = std::fs::File::create("state.json")
-//        .context("Cannot create file to save game state")?;
-//    serde_json::to_writer(state_file, &game.state)
-//        .context("Cannot write game state to file")?;
+        if let Some(resize_args) = event.resize_args() {
+            game.resize(resize_args[0] as f64, resize_args[1] as f64);
+        }
+    }
 
-    Ok(())
+    Ok(game.simulation)
 }
@@ -1,7 +1,21 @@
 extern crate stacked_worlds;
 
+use stacked_worlds::{Options, USAGE};
+
 fn main() {
-    stacked_worlds::run().unwrap_or_else(|err| {
+    let options = Options::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    });
+
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    stacked_worlds::run(options).unwrap_or_else(|err| {
        eprintln!("Error: {}", err);
+       std::process::exit(1);
     });
 }
@@ -0,0 +1,146 @@
+/// Command-line options for the game binary
+
+use error::{Error, GameError};
+
+pub const USAGE: &str = "\
+Usage: stacked-worlds [OPTIONS]
+
+Options:
+    --save FILE             Load the world from FILE, and save it back there on exit; files
+                            ending in .json use JSON instead of RON (default: storage.ron)
+    --default-storage FILE  World to load when the save file does not exist
+                            (default: default-storage.ron)
+    --level FILE            Start a new session in the level FILE instead of loading the
+                            save file; the save file then only holds the session, and edits
+                            to rooms and terrain are saved to FILE
+    --bindings FILE         Load key bindings from FILE (default: bindings.ron)
+    --prefabs FILE          Load object prefabs from FILE (default: prefabs.ron)
+    --window-size WxH       Size of the game window (default: 640x480)
+    --fullscreen            Start in fullscreen mode
+    --headless TICKS        Run TICKS simulation ticks without opening a window, then exit
+    --record FILE           Record all input into a replay file
+    --replay FILE           Play back a replay file instead of live input
+    --no-save               Do not save the world on exit, nor autosave it
+    --autosave SECONDS      Save the world every SECONDS of game time; 0 disables it
+                            (default: 60)
+    --backups N             Keep N previous versions of the save file, as FILE.bak1 (the
+                            most recent) to FILE.bakN (default: 3)
+    --restore-backup N      Load the world from backup N of the save file
+    -h, --help              Print this message";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub save_file: String,
+    pub default_storage: String,
+    pub level: Option<String>,
+    pub bindings_file: String,
+    pub prefabs_file: String,
+    pub window_size: (u32, u32),
+    pub fullscreen: bool,
+    pub headless_ticks: Option<u64>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub no_save: bool,
+    pub autosave_seconds: f64,
+    pub backups: usize,
+    pub restore_backup: Option<usize>,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            save_file: "storage.ron".into(),
+            default_storage: "default-storage.ron".into(),
+            level: None,
+            bindings_file: "bindings.ron".into(),
+            prefabs_file: "prefabs.ron".into(),
+            window_size: (640, 480),
+            fullscreen: false,
+            headless_ticks: None,
+            record: None,
+            replay: None,
+            no_save: false,
+            autosave_seconds: 60.0,
+            backups: 3,
+            restore_backup: None,
+            help: false,
+        }
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    GameError::InvalidArguments { reason }.into()
+}
+
+fn parse_window_size(value: &str) -> Result<(u32, u32), Error> {
+    let mut dimensions = value.splitn(2, 'x').map(|dimension| dimension.parse::<u32>());
+
+    match (dimensions.next(), dimensions.next()) {
+        (Some(Ok(width)), Some(Ok(height))) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid(format!("window size must look like 640x480, not '{}'", value))),
+    }
+}
+
+impl Options {
+    /// Parse the arguments given to the binary, without the program name.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, Error> {
+        let mut options = Options::default();
+
+        while let Some(arg) = args.next() {
+            let value = |args: &mut I| args.next()
+                .ok_or_else(|| invalid(format!("missing value for '{}'", arg)));
+
+            match arg.as_str() {
+                "--save" => options.save_file = value(&mut args)?,
+                "--default-storage" => options.default_storage = value(&mut args)?,
+                "--level" => options.level = Some(value(&mut args)?),
+                "--bindings" => options.bindings_file = value(&mut args)?,
+                "--prefabs" => options.prefabs_file = value(&mut args)?,
+                "--window-size" => options.window_size = parse_window_size(&value(&mut args)?)?,
+                "--fullscreen" => options.fullscreen = true,
+                "--headless" => {
+                    let ticks = value(&mut args)?;
+                    options.headless_ticks = Some(ticks.parse()
+                        .map_err(|_| invalid(format!("tick count must be a number, not '{}'", ticks)))?);
+                },
+                "--record" => options.record = Some(value(&mut args)?),
+                "--replay" => options.replay = Some(value(&mut args)?),
+                "--no-save" => options.no_save = true,
+                "--autosave" => {
+                    let seconds = value(&mut args)?;
+                    options.autosave_seconds = seconds.parse().ok()
+                        .filter(|seconds: &f64| *seconds >= 0.0)
+                        .ok_or_else(|| invalid(format!("autosave interval must be a number of seconds, not '{}'", seconds)))?;
+                },
+                "--backups" => {
+                    let backups = value(&mut args)?;
+                    options.backups = backups.parse()
+                        .map_err(|_| invalid(format!("backup count must be a number, not '{}'", backups)))?;
+                },
+                "--restore-backup" => {
+                    let backup = value(&mut args)?;
+                    options.restore_backup = Some(backup.parse().ok()
+                        .filter(|backup| *backup > 0)
+                        .ok_or_else(|| invalid(format!("backup must be a number starting from 1, not '{}'", backup)))?);
+                },
+                "-h" | "--help" => options.help = true,
+                _ => return Err(invalid(format!("unknown argument '{}'", arg))),
+            }
+        }
+
+        if options.record.is_some() && options.replay.is_some() {
+            return Err(invalid("cannot both --record and --replay at the same time".into()));
+        }
+
+        if options.restore_backup.is_some() && options.replay.is_some() {
+            return Err(invalid("cannot both --restore-backup and --replay at the same time".into()));
+        }
+
+        if options.level.is_some() && (options.replay.is_some() || options.restore_backup.is_some()) {
+            return Err(invalid("--level cannot be combined with --replay or --restore-backup".into()));
+        }
+
+        Ok(options)
+    }
+}
@@ -3,9 +3,9 @@ extern crate nphysics2d;
 extern crate ncollide2d;
 
 use specs::prelude::{WriteStorage, ReadStorage, VecStorage, DenseVecStorage, System, Entities, Join};
-use specs::world::Index;
 use specs::prelude::Entity;
 use specs::prelude::ReadExpect;
+use specs::saveload::{Marker, IntoSerialize, FromDeserialize};
 use nphysics2d::world::World;
 use nphysics2d::object::RigidBody;
 use nphysics2d::object::BodyHandle;
@@ -26,8 +26,9 @@ use ncollide2d::world::CollisionObjectHandle;
 use ncollide2d::world::CollisionGroups;
 use std::collections::HashMap;
 
-use saveload::DestroyEntity;
-use draw::{Position, Size, Shape, ShapeClass};
+use saveload::{DestroyEntity, marker_of, entity_of};
+use error::GameError;
+use draw::{Position, Size, Shape, ShapeClass, TerrainLayer};
 use UpdateDeltaTime;
 
 
@@ -37,11 +38,71 @@ const COLLIDER_MARGIN: f64 = 0.1;
 #[storage(VecStorage)]
 pub struct Room;
 
+/// Part of a room: what sets it apart from the other rooms. Rooms without it use the defaults.
+#[derive(Component, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[storage(VecStorage)]
+#[serde(default)]
+pub struct RoomProperties {
+    /// Name shown to level designers
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Background color as RGB; rooms without one get a color picked from their entity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_color: Option<[f32; 3]>,
+    pub gravity: (f64, f64),
+    /// How quickly objects in the room lose their velocity, per second
+    pub linear_damping: f64,
+}
+
+impl Default for RoomProperties {
+    fn default() -> Self {
+        RoomProperties {
+            name: String::new(),
+            background_color: None,
+            gravity: (0.0, 500.0),
+            linear_damping: 0.0,
+        }
+    }
+}
+
 /// Component that allows an object to physically interact with other objects in the same room
-#[derive(Component, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
 #[storage(VecStorage)]
 pub struct InRoom {
-    pub room_entity: Index,
+    pub room_entity: Entity,
+}
+
+/// Saved form of `InRoom`, with the room referenced by its marker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "InRoom")]
+pub struct InRoomData<M> {
+    pub room_entity: M,
+}
+
+impl <M: Marker> IntoSerialize<M> for InRoom {
+    type Data = InRoomData<M>;
+    type Error = GameError;
+
+    fn into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+        where F: FnMut(Entity) -> Option<M>
+    {
+        Ok(InRoomData {
+            room_entity: marker_of("InRoom", self.room_entity, &mut ids)?,
+        })
+    }
+}
+
+impl <M: Marker> FromDeserialize<M> for InRoom {
+    type Data = InRoomData<M>;
+    type Error = GameError;
+
+    fn from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+        where F: FnMut(M) -> Option<Entity>
+    {
+        Ok(InRoom {
+            room_entity: entity_of("InRoom", data.room_entity, &mut ids)?,
+        })
+    }
 }
 
 #[derive(Component, Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
@@ -79,13 +140,49 @@ pub struct CollisionSet {
     pub time_since_collision: f64,
 }
 
-#[derive(Component, Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
 #[storage(DenseVecStorage)]
 pub struct RevoluteJoint {
-    pub linked_to_entity: Index,
+    pub linked_to_entity: Entity,
+    pub multibody_link: bool,
+}
+
+/// Saved form of `RevoluteJoint`, with the linked entity referenced by its marker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "RevoluteJoint")]
+pub struct RevoluteJointData<M> {
+    pub linked_to_entity: M,
     pub multibody_link: bool,
 }
 
+impl <M: Marker> IntoSerialize<M> for RevoluteJoint {
+    type Data = RevoluteJointData<M>;
+    type Error = GameError;
+
+    fn into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+        where F: FnMut(Entity) -> Option<M>
+    {
+        Ok(RevoluteJointData {
+            linked_to_entity: marker_of("RevoluteJoint", self.linked_to_entity, &mut ids)?,
+            multibody_link: self.multibody_link,
+        })
+    }
+}
+
+impl <M: Marker> FromDeserialize<M> for RevoluteJoint {
+    type Data = RevoluteJointData<M>;
+    type Error = GameError;
+
+    fn from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+        where F: FnMut(M) -> Option<Entity>
+    {
+        Ok(RevoluteJoint {
+            linked_to_entity: entity_of("RevoluteJoint", data.linked_to_entity, &mut ids)?,
+            multibody_link: data.multibody_link,
+        })
+    }
+}
+
 struct PhysicalObject {
     body_handle: BodyHandle,
     collision_object_handle: CollisionObjectHandle,
@@ -103,8 +200,14 @@ struct PhysicalRoom {
     physical_objects: HashMap<Entity, PhysicalObject>,
     collision_object_to_entity: HashMap<CollisionObjectHandle, Entity>,
     physical_constraints: HashMap<Entity, PhysicalConstraint>,
+
+    /// Bounds of the room and of its terrain boxes when the physics world was built
+    layout: RoomLayout,
 }
 
+/// The room's size followed by the entity and bounds of each terrain box, in join order
+type RoomLayout = Vec<(Entity, [f64; 4])>;
+
 trait GetEntity {
     fn get_entity(&self) -> Option<Entity>;
 }
@@ -164,11 +267,51 @@ impl<'a> System<'a> for PhysicsSystem {
         WriteStorage<'a, CollisionSet>,
         ReadStorage<'a, RevoluteJoint>,
         ReadStorage<'a, DestroyEntity>,
+        ReadStorage<'a, TerrainLayer>,
+        ReadStorage<'a, RoomProperties>,
         ReadExpect<'a, UpdateDeltaTime>,
     );
 
     fn run(&mut self, (entities, rooms, in_rooms, sizes, shapes, mut positions, mut velocities,
-        forces, mut aims, mut collision_sets, revolute_joints, destroy_entities, delta_time): Self::SystemData) {
+        forces, mut aims, mut collision_sets, revolute_joints, destroy_entities, terrain_layers,
+        room_properties, delta_time): Self::SystemData) {
+        // Background and foreground terrain is only decoration
+        let is_solid = |entity: Entity| terrain_layers.get(entity)
+            .map_or(true, |terrain_layer| *terrain_layer == TerrainLayer::Collision);
+
+        // Rooms can disappear without a DestroyEntity, e.g. when the world is replaced by a
+        // quickload; their objects go away along with their physics world
+        self.physical_rooms.retain(|room_entity, _physical_room| entities.is_alive(*room_entity));
+
+        // Walls and terrain colliders are only built along with the physics world, so a room
+        // whose walls or terrain were moved or resized is rebuilt from scratch; its objects are
+        // re-created from their components
+        let mut layouts: HashMap<Entity, RoomLayout> = HashMap::new();
+
+        for (entity, _room, size) in (&*entities, &rooms, &sizes).join() {
+            layouts.insert(entity, vec![(entity, [0.0, 0.0, size.width, size.height])]);
+        }
+
+        for (entity, in_room, position, size, ()) in (&*entities, &in_rooms, &positions, &sizes, !&velocities).join() {
+            if !is_solid(entity) {
+                continue;
+            }
+
+            if let Some(layout) = layouts.get_mut(&in_room.room_entity) {
+                layout.push((entity, [position.x, position.y, size.width, size.height]));
+            }
+        }
+
+        self.physical_rooms.retain(|room_entity, physical_room| {
+            let unchanged = layouts.get(room_entity) == Some(&physical_room.layout);
+
+            if !unchanged {
+                println!("Rebuilding room {:?}", room_entity);
+            }
+
+            unchanged
+        });
+
         // Clear the visited flag of all physical objects and joints; after processing entities, all
         // unvisited ones will be deleted
         for room in self.physical_rooms.values_mut() {
@@ -186,8 +329,6 @@ impl<'a> System<'a> for PhysicsSystem {
                 .or_insert_with(|| {
                     let mut world = World::new();
 
-                    world.set_gravity(Vector2::new(0.0, 500.0));
-
                     fn create_wall(world: &mut World<f64>, normal: Vector2<f64>, isometry: Isometry2<f64>) -> CollisionObjectHandle {
                         world.add_collider(
                             COLLIDER_MARGIN,
@@ -228,6 +369,7 @@ impl<'a> System<'a> for PhysicsSystem {
                         physical_objects: HashMap::new(),
                         physical_constraints: HashMap::new(),
                         collision_object_to_entity,
+                        layout: layouts.remove(&entity).unwrap_or_default(),
                     }
                 });
         }
@@ -235,7 +377,11 @@ impl<'a> System<'a> for PhysicsSystem {
         // Find static objects in the room, and create terrain out of them
         // FIXME: Maybe consider using Shape instead of Size
         for (entity, in_room, position, size, ()) in (&*entities, &in_rooms, &positions, &sizes, !&velocities).join() {
-            let room_entity = entities.entity(in_room.room_entity);
+            if !is_solid(entity) {
+                continue;
+            }
+
+            let room_entity = in_room.room_entity;
 
             let room = match self.physical_rooms.get_mut(&room_entity) {
                 Some(physical_room) => physical_room,
@@ -277,14 +423,14 @@ impl<'a> System<'a> for PhysicsSystem {
         }
 
         for (entity, in_room, shape, position, velocity) in (&*entities, &in_rooms, &shapes, &mut positions, &mut velocities).join() {
-            let room_entity = entities.entity(in_room.room_entity);
+            let room_entity = in_room.room_entity;
 
             let (multibody_parent_handle, multibody_parent_entity) = {
                 if let Some(revolute_joint) = revolute_joints.get(entity) {
                     if revolute_joint.multibody_link {
-                        let linked_to_entity = entities.entity(revolute_joint.linked_to_entity);
+                        let linked_to_entity = revolute_joint.linked_to_entity;
                         if let Some(in_room) = in_rooms.get(linked_to_entity) {
-                            let body_handle = self.get_body_handle(&linked_to_entity, &entities.entity(in_room.room_entity));
+                            let body_handle = self.get_body_handle(&linked_to_entity, &in_room.room_entity);
 
                             (body_handle, Some(linked_to_entity))
                         } else {
@@ -301,11 +447,11 @@ impl<'a> System<'a> for PhysicsSystem {
 //            let (multibody_parent_handle, multibody_parent_entity) = {
 //                if let Some(revolute_joint) = revolute_joints.get(entity) {
 //                    if revolute_joint.multibody_link {
-//                        let linked_to_entity = entities.entity(revolute_joint.linked_to_entity);
+//                        let linked_to_entity = revolute_joint.linked_to_entity;
 //                        if let Some(in_room) = in_rooms.get(linked_to_entity) {
-//                            if linked_to_entity.id() == in_room.room_entity {
+//                            if linked_to_entity == in_room.room_entity {
 //                                (Some(BodyHandle::ground()), Some(linked_to_entity))
-//                            } else if let Some(room) = self.physical_rooms.get(&entities.entity(in_room.room_entity)) {
+//                            } else if let Some(room) = self.physical_rooms.get(&in_room.room_entity) {
 //                                let body_handle = room.physical_objects
 //                                    .get(&linked_to_entity)
 //                                    .map(|object| object.body_handle);
@@ -418,21 +564,21 @@ impl<'a> System<'a> for PhysicsSystem {
                 continue;
             }
 
-            let room_entity = entities.entity(in_room.room_entity);
+            let room_entity = in_room.room_entity;
 
-            let entity2 = entities.entity(revolute_joint.linked_to_entity);
+            let entity2 = revolute_joint.linked_to_entity;
 
             let body1 = self.get_body_handle(&entity, &room_entity);
             let body2 = self.get_body_handle(&entity2, &room_entity);
 
             let () = if let Some(in_room2) = in_rooms.get(entity2) {
-                if in_room2.room_entity != room_entity.id() {
+                if in_room2.room_entity != room_entity {
                     eprintln!("Rooms do not match for entities linked by RevoluteJoint");
                     continue
                 } else {
                     () // Ok
                 }
-            } else if entity2.id() == in_room.room_entity {
+            } else if entity2 == in_room.room_entity {
                 () // Ok
             } else {
                 eprintln!("Could not find room for entity linked by RevoluteJoint");
@@ -521,7 +667,7 @@ impl<'a> System<'a> for PhysicsSystem {
                 continue;
             }
 
-            let room = match self.physical_rooms.get(&entities.entity(in_room.room_entity)) {
+            let room = match self.physical_rooms.get(&in_room.room_entity) {
                 Some(physical_room) => physical_room,
                 None => {
                     println!("Could not find room for entity with Aim");
@@ -561,7 +707,7 @@ impl<'a> System<'a> for PhysicsSystem {
         // But until https://github.com/sebcrozet/nphysics/issues/107 is fixed we can't use that
         // FIXME: Handle 'force' component deletion (e.g. by resetting forces to 0 every update)
         for (entity, in_room, force) in (&*entities, &in_rooms, &forces).join() {
-            if let Some(room) = self.physical_rooms.get_mut(&entities.entity(in_room.room_entity)) {
+            if let Some(room) = self.physical_rooms.get_mut(&in_room.room_entity) {
                 if let Some(physical_object) = room.physical_objects.get(&entity) {
                     let force_generator = room.world.force_generator_mut(room.force_generator);
 
@@ -573,7 +719,7 @@ impl<'a> System<'a> for PhysicsSystem {
         }
 
         for (entity, in_room, force) in (&*entities, &in_rooms, &forces).join() {
-            if let Some(rigid_body) = self.get_rigid_body(&entity, &entities.entity(in_room.room_entity)) {
+            if let Some(rigid_body) = self.get_rigid_body(&entity, &in_room.room_entity) {
                 let impulse_force = Vector2::new(force.impulse.0, force.impulse.1);
 
                 let velocity = rigid_body.velocity().clone();
@@ -596,11 +742,11 @@ impl<'a> System<'a> for PhysicsSystem {
 
         for (entity, revolute_joint, in_room) in (&*entities, &revolute_joints, &in_rooms).join() {
             let target_will_be_destroyed = destroy_entities
-                .get(entities.entity(revolute_joint.linked_to_entity))
+                .get(revolute_joint.linked_to_entity)
                 .is_some();
 
             if target_will_be_destroyed {
-                let room = match self.physical_rooms.get_mut(&entities.entity(in_room.room_entity)) {
+                let room = match self.physical_rooms.get_mut(&in_room.room_entity) {
                     Some(physical_room) => physical_room,
                     None => continue,
                 };
@@ -612,7 +758,7 @@ impl<'a> System<'a> for PhysicsSystem {
         }
 
         for (entity, _destroy_entity, in_room) in (&*entities, &destroy_entities, &in_rooms).join() {
-            if let Some(room) = self.physical_rooms.get_mut(&entities.entity(in_room.room_entity)) {
+            if let Some(room) = self.physical_rooms.get_mut(&in_room.room_entity) {
                 if let Some(physical_object) = room.physical_objects.remove(&entity) {
                     room.collision_object_to_entity.remove(&physical_object.collision_object_handle);
 
@@ -639,20 +785,45 @@ impl<'a> System<'a> for PhysicsSystem {
                 }
             }
 
+            // The room's objects went away with its physics world; `DestroyEntities` deletes
+            // their entities too
             println!("Destroyed room {:?}", entity);
-            // FIXME: destroy objects in the room too
         }
 
         for (entity, in_room, _destroy_entity, _revolute_joint) in (&*entities, &in_rooms, &destroy_entities, &revolute_joints).join() {
-            if let Some(room) = self.physical_rooms.get_mut(&entities.entity(in_room.room_entity)) {
+            if let Some(room) = self.physical_rooms.get_mut(&in_room.room_entity) {
                 if let Some(physical_constraint) = room.physical_constraints.remove(&entity) {
                     room.world.remove_constraint(physical_constraint.revolute_constraint_handle);
                 }
             }
         }
 
-        // Let time flow in the physics world
-        for physical_room in self.physical_rooms.values_mut() {
+        // Let time flow in the physics world, under the room's own gravity and damping; both are
+        // applied every step so that edits to the room's properties take effect right away
+        let default_properties = RoomProperties::default();
+
+        for (room_entity, physical_room) in self.physical_rooms.iter_mut() {
+            let properties = room_properties.get(*room_entity).unwrap_or(&default_properties);
+            let (gravity_x, gravity_y) = properties.gravity;
+
+            physical_room.world.set_gravity(Vector2::new(gravity_x, gravity_y));
+
+            if properties.linear_damping > 0.0 {
+                let damping = 1.0 / (1.0 + delta_time.dt * properties.linear_damping);
+
+                for physical_object in physical_room.physical_objects.values() {
+                    // Links of a multibody move along with their parent
+                    if physical_object.multibody_parent.is_some() {
+                        continue;
+                    }
+
+                    if let Some(body) = physical_room.world.rigid_body_mut(physical_object.body_handle) {
+                        let velocity = body.velocity().clone();
+                        body.set_velocity(Velocity2::new(velocity.linear * damping, velocity.angular * damping));
+                    }
+                }
+            }
+
             physical_room.world.set_timestep(delta_time.dt);
             physical_room.world.step();
         }
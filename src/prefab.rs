@@ -0,0 +1,208 @@
+/// Prefabs: named bundles of components with their default values
+///
+/// Every object the game or the editor creates is spawned from a prefab, and then given its
+/// instance-specific components (position, room, links). The built-in prefabs can be overridden
+/// and new ones added in a RON file mapping names to component bundles, e.g.:
+///
+/// ```text
+/// {
+///     "ball": (shape: Some((size: 10, class: Ball)), velocity: Some((x: 0, y: 0))),
+///     "big_ball": (shape: Some((size: 25, class: Ball)), velocity: Some((x: 0, y: 0))),
+/// }
+/// ```
+///
+/// Components that refer to other entities (`Shifter`, `ChainLink`) can only be switched on, and
+/// start out with their defaults.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use specs::prelude::{Builder, Component};
+use specs::world::{EntitiesRes, LazyBuilder};
+use specs::LazyUpdate;
+use ron;
+
+use error::{Error, ResultExt};
+use draw::{Shape, ShapeClass};
+use physics::{Room, Velocity, Force, Aim, CollisionSet};
+use input::PlayerController;
+use control::{Jump, ChainLink};
+use shift::Shifter;
+use animate::{Animation, RoomAnimation};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Prefab {
+    pub shape: Option<Shape>,
+    pub room: Option<Room>,
+    pub room_animation: Option<Animation<RoomAnimation>>,
+    pub velocity: Option<Velocity>,
+    pub force: Option<Force>,
+    pub aim: Option<Aim>,
+    pub collision_set: Option<CollisionSet>,
+    pub player_controller: Option<PlayerController>,
+    pub jump: Option<Jump>,
+    pub shifter: bool,
+    pub chain_link: bool,
+}
+
+fn with_optional<'a, C>(builder: LazyBuilder<'a>, component: Option<C>) -> LazyBuilder<'a>
+    where C: Component + Send + Sync
+{
+    match component {
+        Some(component) => builder.with(component),
+        None => builder,
+    }
+}
+
+impl Prefab {
+    /// Queue the creation of an entity with this prefab's components; the caller adds the rest,
+    /// marks it and builds it.
+    pub fn spawn<'a>(&self, entities: &'a EntitiesRes, lazy_update: &'a LazyUpdate) -> LazyBuilder<'a> {
+        let builder = lazy_update.create_entity(entities);
+
+        let builder = with_optional(builder, self.shape);
+        let builder = with_optional(builder, self.room);
+        let builder = with_optional(builder, self.room_animation.clone());
+        let builder = with_optional(builder, self.velocity);
+        let builder = with_optional(builder, self.force);
+        let builder = with_optional(builder, self.aim);
+        let builder = with_optional(builder, self.collision_set);
+        let builder = with_optional(builder, self.player_controller);
+        let builder = with_optional(builder, self.jump);
+        let builder = with_optional(builder, if self.shifter { Some(Shifter::default()) } else { None });
+
+        with_optional(builder, if self.chain_link { Some(ChainLink::default()) } else { None })
+    }
+}
+
+/// Resource holding every known prefab by name.
+#[derive(Debug, Clone)]
+pub struct Prefabs {
+    prefabs: BTreeMap<String, Prefab>,
+}
+
+impl Default for Prefabs {
+    fn default() -> Self {
+        let mut prefabs = BTreeMap::new();
+
+        prefabs.insert("room".into(), Prefab {
+            room: Some(Room),
+            room_animation: Some(Animation::new(32)),
+            .. Prefab::default()
+        });
+
+        prefabs.insert("terrain".into(), Prefab {
+            room_animation: Some(Animation::new(32)),
+            .. Prefab::default()
+        });
+
+        prefabs.insert("ball".into(), Prefab {
+            shape: Some(Shape { size: 10.0, class: ShapeClass::Ball }),
+            velocity: Some(Velocity::default()),
+            .. Prefab::default()
+        });
+
+        prefabs.insert("player".into(), Prefab {
+            shape: Some(Shape { size: 10.0, class: ShapeClass::Ball }),
+            velocity: Some(Velocity::default()),
+            force: Some(Force::default()),
+            aim: Some(Aim::default()),
+            collision_set: Some(CollisionSet::default()),
+            player_controller: Some(PlayerController::default()),
+            jump: Some(Jump::default()),
+            shifter: true,
+            .. Prefab::default()
+        });
+
+        prefabs.insert("chain_link".into(), Prefab {
+            shape: Some(Shape { size: 3.0, class: ShapeClass::ChainLink }),
+            velocity: Some(Velocity::default()),
+            chain_link: true,
+            .. Prefab::default()
+        });
+
+        Prefabs { prefabs }
+    }
+}
+
+impl Prefabs {
+    /// Load prefabs from `file_name` on top of the built-in ones; a missing file is not an error.
+    pub fn load(file_name: &str) -> Result<Self, Error> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut prefabs = Prefabs::default();
+
+        let mut file = match File::open(file_name) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == ::std::io::ErrorKind::NotFound => return Ok(prefabs),
+            Err(error) => return Err(Error::from(error)
+                .context(format!("Cannot open prefabs file '{}'", file_name)).into()),
+        };
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .context(format!("Cannot read prefabs file '{}'", file_name))?;
+
+        let loaded: BTreeMap<String, Prefab> = ron::de::from_str(&contents)
+            .context(format!("Cannot deserialize prefabs file '{}'", file_name))?;
+
+        prefabs.prefabs.extend(loaded);
+
+        Ok(prefabs)
+    }
+
+    /// Write every prefab, built-in ones included, in the format of a prefabs file.
+    pub fn to_ron_string(&self) -> Result<String, Error> {
+        let mut serializer = ron::ser::Serializer::new(Some(Default::default()), true);
+        ::serde::Serialize::serialize(&self.prefabs, &mut serializer)
+            .context("Cannot serialize prefabs")?;
+
+        Ok(serializer.into_output_string())
+    }
+
+    /// Read prefabs written by `to_ron_string`, on top of the built-in ones.
+    pub fn from_ron_str(contents: &str) -> Result<Self, Error> {
+        let mut prefabs = Prefabs::default();
+
+        let loaded: BTreeMap<String, Prefab> = ron::de::from_str(contents)
+            .context("Cannot deserialize prefabs")?;
+
+        prefabs.prefabs.extend(loaded);
+
+        Ok(prefabs)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    /// Every prefab with its name, in alphabetical order.
+    pub fn iter(&self) -> btree_map::Iter<String, Prefab> {
+        self.prefabs.iter()
+    }
+
+    /// Spawn the prefab called `name`, or nothing if there is no such prefab. The built-in
+    /// prefabs always exist, since a prefabs file can only override them.
+    pub fn spawn<'a>(&self, name: &str, entities: &'a EntitiesRes, lazy_update: &'a LazyUpdate) -> Option<LazyBuilder<'a>> {
+        self.get(name).map(|prefab| prefab.spawn(entities, lazy_update))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use specs::prelude::World;
+    use specs::LazyUpdate;
+    use super::Prefabs;
+
+    #[test]
+    fn unknown_prefabs_spawn_nothing() {
+        let world = World::new();
+        let entities = world.entities();
+        let lazy_update = world.read_resource::<LazyUpdate>();
+        let prefabs = Prefabs::default();
+
+        assert!(prefabs.spawn("ball", &entities, &lazy_update).is_some());
+        assert!(prefabs.spawn("bal", &entities, &lazy_update).is_none());
+    }
+}
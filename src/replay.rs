@@ -0,0 +1,138 @@
+/// Input recording and deterministic replay
+///
+/// A replay file starts with a header holding the save the session started from, the key
+/// bindings in use (raw button presses are recorded, not actions) and the prefabs new objects are
+/// spawned from, followed by one record per
+/// simulation tick with the input events that tick consumed. The file is written as JSON lines,
+/// one record per line, so that a session that ends in a crash still leaves behind a replay of
+/// everything up to the crash.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use serde_json;
+
+use error::{Error, ResultExt};
+use input::InputEvent;
+use action::ActionMap;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplayHeader {
+    /// Contents of the save the recorded session started from
+    pub initial_world: String,
+    pub bindings: ActionMap,
+    /// Prefabs in use, in the format of a prefabs file; kept as RON text like the save, since
+    /// JSON cannot tell a unit component from a missing one
+    pub prefabs: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplayTick {
+    pub dt: f64,
+    pub events: Vec<InputEvent>,
+}
+
+pub struct ReplayRecorder {
+    writer: BufWriter<File>,
+}
+
+impl ReplayRecorder {
+    pub fn create(file_name: &str, initial_world: String, bindings: ActionMap, prefabs: String) -> Result<Self, Error> {
+        let file = File::create(file_name)
+            .context(format!("Cannot create replay file '{}'", file_name))?;
+
+        let mut recorder = ReplayRecorder {
+            writer: BufWriter::new(file),
+        };
+
+        recorder.write_record(&ReplayHeader { initial_world, bindings, prefabs })?;
+
+        Ok(recorder)
+    }
+
+    pub fn record_tick(&mut self, tick: &ReplayTick) -> Result<(), Error> {
+        self.write_record(tick)
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.writer.flush()
+            .context("Cannot write replay file")?;
+
+        Ok(())
+    }
+
+    fn write_record<T: ::serde::Serialize>(&mut self, record: &T) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, record)
+            .context("Cannot serialize replay record")?;
+        self.writer.write_all(b"\n")
+            .context("Cannot write replay file")?;
+
+        Ok(())
+    }
+}
+
+pub struct ReplayPlayer {
+    header: ReplayHeader,
+    ticks: Vec<ReplayTick>,
+    next_tick: usize,
+}
+
+impl ReplayPlayer {
+    pub fn open(file_name: &str) -> Result<Self, Error> {
+        let file = File::open(file_name)
+            .context(format!("Cannot open replay file '{}'", file_name))?;
+
+        let mut lines = BufReader::new(file).lines();
+
+        let header = match lines.next() {
+            Some(line) => {
+                let line = line.context("Cannot read replay file")?;
+                serde_json::from_str::<ReplayHeader>(&line)
+                    .context("Cannot deserialize replay header")?
+            },
+            None => return Err(format_err!("Replay file '{}' is empty", file_name)),
+        };
+
+        let mut ticks = Vec::new();
+
+        for line in lines {
+            let line = line.context("Cannot read replay file")?;
+
+            // A recording interrupted by a crash may end in a partially written line
+            match serde_json::from_str::<ReplayTick>(&line) {
+                Ok(tick) => ticks.push(tick),
+                Err(error) => {
+                    eprintln!("Replay truncated after {} ticks: {}", ticks.len(), error);
+                    break;
+                },
+            }
+        }
+
+        Ok(ReplayPlayer { header, ticks, next_tick: 0 })
+    }
+
+    pub fn initial_world(&self) -> &str {
+        &self.header.initial_world
+    }
+
+    pub fn bindings(&self) -> &ActionMap {
+        &self.header.bindings
+    }
+
+    pub fn prefabs(&self) -> &str {
+        &self.header.prefabs
+    }
+
+    pub fn next_tick(&mut self) -> Option<ReplayTick> {
+        let tick = self.ticks.get(self.next_tick).cloned();
+
+        if tick.is_some() {
+            self.next_tick += 1;
+        }
+
+        tick
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next_tick >= self.ticks.len()
+    }
+}
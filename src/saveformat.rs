@@ -0,0 +1,561 @@
+/// On-disk format of saved worlds
+///
+/// A save is a `SaveFile` with a format version, an optional level file that it builds upon, the
+/// editor grid of levels, and one `EntityRecord` per saved entity. Each
+/// component is stored under its own name, so adding a component to the game does not affect
+/// existing files; records written before a component existed simply load without it.
+///
+/// Older formats are upgraded on load:
+/// * Version 0: a bare list of specs' `EntityData` with a positional tuple of components, where
+///   references to other entities are raw entity indices
+/// * Version 1: same as version 0, but references are `U64Marker`s
+/// * Version 2: named-field format
+/// * Version 3: a save may be a session that only holds dynamic state, and refers to a separate
+///   level file for the rooms and terrain
+/// * Version 4: the current format, where rooms are linked to the room that objects shift to
+///   instead of shifting to the next room in the file
+///
+/// Saves are written as RON, or as JSON when the file name ends in `.json`; both hold the same
+/// data, and legacy formats only ever existed as RON. JSON writes the unit struct `Room` as `null`,
+/// which reads back as no room at all, so JSON saves mark rooms with `"room": true` instead.
+///
+/// When a component field is added, give it a `#[serde(default)]`. When a field is renamed or
+/// changes meaning, bump `SAVE_VERSION` and add a step to `migrate` that converts records from
+/// the previous version.
+
+use specs::saveload::U64Marker;
+use serde::de::DeserializeOwned;
+use ron;
+use serde_json;
+use serde_json::{Value, Map};
+use std::path::Path;
+
+use error::{Error, ResultExt};
+use draw::{Position, Size, Shape, TerrainLayer};
+use shift::{ShifterData, RoomLinkData};
+use animate::{Animation, RoomAnimation};
+use physics::{Room, RoomProperties, InRoomData, Force, Velocity, CollisionSet, RevoluteJointData, Aim};
+use input::PlayerController;
+use control::{Jump, ChainLinkData};
+use edit::{SpawnPoint, Grid};
+
+pub const SAVE_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveFile {
+    pub version: u32,
+    /// Level file whose entities must be loaded before this file's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    /// Editor grid settings of a level; sessions use the grid of their level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grid: Option<Grid>,
+    pub entities: Vec<EntityRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityRecord {
+    pub marker: U64Marker,
+    #[serde(default)]
+    pub components: Components,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Components {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<Size>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shape: Option<Shape>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<Room>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_room: Option<InRoomData<U64Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_controller: Option<PlayerController>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<Velocity>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force: Option<Force>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aim: Option<Aim>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collision_set: Option<CollisionSet>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revolute_joint: Option<RevoluteJointData<U64Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_link: Option<ChainLinkData<U64Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shifter: Option<ShifterData<U64Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jump: Option<Jump>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_animation: Option<Animation<RoomAnimation>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spawn_point: Option<SpawnPoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_link: Option<RoomLinkData<U64Marker>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terrain_layer: Option<TerrainLayer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_properties: Option<RoomProperties>,
+}
+
+impl Components {
+    /// Replace every reference to another entity with the marker `map` returns for it; a reference
+    /// that `map` returns `None` for is kept as it is.
+    pub fn remap_references<F>(&mut self, mut map: F)
+        where F: FnMut(&U64Marker) -> Option<U64Marker>
+    {
+        let mut remap = |marker: &mut U64Marker| {
+            if let Some(new_marker) = map(marker) {
+                *marker = new_marker;
+            }
+        };
+
+        if let Some(ref mut in_room) = self.in_room {
+            remap(&mut in_room.room_entity);
+        }
+
+        if let Some(ref mut revolute_joint) = self.revolute_joint {
+            remap(&mut revolute_joint.linked_to_entity);
+        }
+
+        if let Some(ChainLinkData { next_link: Some(ref mut next_link), .. }) = self.chain_link {
+            remap(next_link);
+        }
+
+        if let Some(ref mut shifter) = self.shifter {
+            if let Some(ref mut target_room) = shifter.target_room {
+                remap(target_room);
+            }
+
+            if let Some(ref mut target_entity) = shifter.target_entity {
+                remap(target_entity);
+            }
+        }
+
+        if let Some(ref mut room_link) = self.room_link {
+            remap(&mut room_link.target_room);
+        }
+    }
+}
+
+/// Components of versions 0 and 1, in the order of the old `SerializeComponents` tuple; `R` is
+/// the type used to refer to other entities.
+type LegacyComponents<R> = (
+    Option<Position>,
+    Option<Size>,
+    Option<Shape>,
+    Option<Room>,
+    Option<InRoomData<R>>,
+    Option<PlayerController>,
+    Option<Velocity>,
+    Option<Force>,
+    Option<Aim>,
+    Option<CollisionSet>,
+    Option<RevoluteJointData<R>>,
+    Option<ChainLinkData<R>>,
+    Option<ShifterData<R>>,
+    Option<Jump>,
+    Option<Animation<RoomAnimation>>,
+);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename = "EntityData")]
+struct LegacyEntityData<R> {
+    marker: U64Marker,
+    components: LegacyComponents<R>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    Ron,
+    Json,
+}
+
+impl SaveFormat {
+    /// Pick the format from the extension of `file_name`, defaulting to RON. Backups are in the
+    /// format of the save they were made from, so a trailing `.bakN` is skipped.
+    pub fn from_file_name(file_name: &str) -> Self {
+        let extension = |path: &Path| path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+
+        let mut path = Path::new(file_name);
+
+        let is_backup = extension(path).map_or(false, |extension| extension.len() > 3 &&
+            extension.starts_with("bak") && extension[3..].chars().all(|c| c.is_ascii_digit()));
+
+        if is_backup {
+            if let Some(file_stem) = path.file_stem() {
+                path = Path::new(file_stem);
+            }
+        }
+
+        let is_json = extension(path).map_or(false, |extension| extension == "json");
+
+        if is_json { SaveFormat::Json } else { SaveFormat::Ron }
+    }
+}
+
+pub fn serialize_save(save_file: &SaveFile, format: SaveFormat) -> Result<String, Error> {
+    match format {
+        SaveFormat::Ron => {
+            let mut serializer = ron::ser::Serializer::new(Some(Default::default()), true);
+            ::serde::Serialize::serialize(save_file, &mut serializer)?;
+
+            Ok(serializer.into_output_string())
+        },
+        SaveFormat::Json => {
+            let mut value = serde_json::to_value(save_file)?;
+
+            for components in json_components(&mut value) {
+                if let Some(room) = components.and_then(|components| components.get_mut("room")) {
+                    *room = Value::Bool(true);
+                }
+            }
+
+            Ok(serde_json::to_string_pretty(&value)?)
+        },
+    }
+}
+
+/// The `components` of each entity record in a JSON save, in order; `None` for records that have
+/// none.
+fn json_components(save_file: &mut Value) -> Vec<Option<&mut Map<String, Value>>> {
+    match save_file.get_mut("entities").and_then(Value::as_array_mut) {
+        Some(entities) => entities.iter_mut()
+            .map(|record| record.get_mut("components").and_then(Value::as_object_mut))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Parse a save in any known format version, and upgrade it to the current one.
+pub fn deserialize_save(contents: &[u8], format: SaveFormat) -> Result<SaveFile, Error> {
+    if format == SaveFormat::Json {
+        let mut value: Value = serde_json::from_slice(contents)
+            .context("Cannot deserialize JSON save")?;
+
+        // Saves written before rooms were marked with `true` have `null`; rooms are the only
+        // entities with the field at all
+        let rooms: Vec<bool> = json_components(&mut value).into_iter()
+            .map(|components| match components.and_then(|components| components.remove("room")) {
+                Some(Value::Bool(is_room)) => is_room,
+                Some(_) => true,
+                None => false,
+            })
+            .collect();
+
+        let mut save_file: SaveFile = serde_json::from_value(value)
+            .context("Cannot deserialize JSON save")?;
+
+        for (record, is_room) in save_file.entities.iter_mut().zip(rooms) {
+            if is_room {
+                record.components.room = Some(Room);
+            }
+        }
+
+        return migrate(save_file);
+    }
+
+    let legacy = contents.iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .map_or(false, |&byte| byte == b'[');
+
+    let save_file = if legacy {
+        // Try the newer of the two legacy formats first; they only differ in references
+        match deserialize_legacy::<U64Marker>(contents) {
+            Ok(entities) => SaveFile {
+                version: 1,
+                level: None,
+                grid: None,
+                entities: upgrade_legacy(entities, |marker| Some(marker))?,
+            },
+            Err(_) => {
+                let entities = deserialize_legacy::<u32>(contents)
+                    .context("Cannot deserialize legacy save")?;
+
+                // Entity indices were allocated in order, so an index is the position of the
+                // entity in the file, as long as no entities were deleted before saving.
+                let markers: Vec<U64Marker> = entities.iter()
+                    .map(|entity_data| entity_data.marker.clone())
+                    .collect();
+
+                SaveFile {
+                    version: 0,
+                    level: None,
+                    grid: None,
+                    entities: upgrade_legacy(entities, |index| markers.get(index as usize).cloned())?,
+                }
+            },
+        }
+    } else {
+        from_ron(contents)?
+    };
+
+    migrate(save_file)
+}
+
+fn from_ron<T: DeserializeOwned>(contents: &[u8]) -> Result<T, Error> {
+    let mut deserializer = ron::de::Deserializer::from_bytes(contents)?;
+
+    Ok(T::deserialize(&mut deserializer)?)
+}
+
+fn deserialize_legacy<R: DeserializeOwned>(contents: &[u8]) -> Result<Vec<LegacyEntityData<R>>, Error> {
+    from_ron(contents)
+}
+
+/// Bring a save file up to `SAVE_VERSION`.
+fn migrate(mut save_file: SaveFile) -> Result<SaveFile, Error> {
+    if save_file.version > SAVE_VERSION {
+        return Err(format_err!("save format version {} is newer than the supported version {}",
+                               save_file.version, SAVE_VERSION));
+    }
+
+    // Versions 0 and 1 were converted to named records while parsing
+    if save_file.version < 2 {
+        save_file.version = 2;
+    }
+
+    // Version 3 only added the optional level reference
+    if save_file.version < 3 {
+        save_file.version = 3;
+    }
+
+    // Objects used to shift to the next room, wrapping around; link the rooms that way
+    if save_file.version < 4 {
+        let rooms: Vec<usize> = save_file.entities.iter()
+            .enumerate()
+            .filter(|&(_index, record)| record.components.room.is_some())
+            .map(|(index, _record)| index)
+            .collect();
+
+        for (position, &index) in rooms.iter().enumerate() {
+            let next_room = rooms[(position + 1) % rooms.len()];
+
+            if next_room != index {
+                let target_room = save_file.entities[next_room].marker.clone();
+                save_file.entities[index].components.room_link = Some(RoomLinkData { target_room });
+            }
+        }
+
+        save_file.version = 4;
+    }
+
+    Ok(save_file)
+}
+
+/// Convert positional records to named ones, turning references into markers with `marker_of`.
+fn upgrade_legacy<R, F>(entities: Vec<LegacyEntityData<R>>, mut marker_of: F) -> Result<Vec<EntityRecord>, Error>
+    where F: FnMut(R) -> Option<U64Marker>
+{
+    let mut records = Vec::with_capacity(entities.len());
+
+    for LegacyEntityData { marker, components } in entities {
+        let (position, size, shape, room, in_room, player_controller, velocity, force, aim,
+            collision_set, revolute_joint, chain_link, shifter, jump, room_animation) = components;
+
+        let mut reference = |reference: R| marker_of(reference)
+            .ok_or_else(|| format_err!("entity {:?} refers to an entity that is not in the save", marker));
+
+        let in_room = match in_room {
+            Some(in_room) => Some(InRoomData { room_entity: reference(in_room.room_entity)? }),
+            None => None,
+        };
+
+        let revolute_joint = match revolute_joint {
+            Some(revolute_joint) => Some(RevoluteJointData {
+                linked_to_entity: reference(revolute_joint.linked_to_entity)?,
+                multibody_link: revolute_joint.multibody_link,
+            }),
+            None => None,
+        };
+
+        let chain_link = match chain_link {
+            Some(chain_link) => Some(ChainLinkData {
+                creation_animation: chain_link.creation_animation,
+                destruction_animation: chain_link.destruction_animation,
+                expire: chain_link.expire,
+                next_link: match chain_link.next_link {
+                    Some(next_link) => Some(reference(next_link)?),
+                    None => None,
+                },
+            }),
+            None => None,
+        };
+
+        let shifter = match shifter {
+            Some(shifter) => Some(ShifterData {
+                target_room: match shifter.target_room {
+                    Some(target_room) => Some(reference(target_room)?),
+                    None => None,
+                },
+                target_entity: match shifter.target_entity {
+                    Some(target_entity) => Some(reference(target_entity)?),
+                    None => None,
+                },
+                shifting: shifter.shifting,
+                sensing: shifter.sensing,
+            }),
+            None => None,
+        };
+
+        records.push(EntityRecord {
+            marker,
+            components: Components {
+                position, size, shape, room, in_room, player_controller, velocity, force, aim,
+                collision_set, revolute_joint, chain_link, shifter, jump, room_animation,
+                spawn_point: None,
+                room_link: None,
+                terrain_layer: None,
+                room_properties: None,
+            },
+        });
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use draw::ShapeClass;
+    use edit::SpawnKind;
+    use input::Movement;
+
+    /// A save with one entity that has every saved component, so that a round trip covers them all
+    fn every_component() -> SaveFile {
+        let other = U64Marker(2);
+
+        let components = Components {
+            position: Some(Position { x: 1.5, y: 2.5 }),
+            size: Some(Size { width: 30.0, height: 40.0 }),
+            shape: Some(Shape { size: 8.0, class: ShapeClass::Ball }),
+            room: Some(Room),
+            in_room: Some(InRoomData { room_entity: other }),
+            player_controller: Some(PlayerController { moving: Movement::Left, jumping: true, ..Default::default() }),
+            velocity: Some(Velocity { x: 0.1, y: -0.2 }),
+            force: Some(Force { continuous: (1.0, 2.0), impulse: (3.0, 4.0) }),
+            aim: Some(Aim { aiming: true, aiming_toward: (5.0, 6.0), ..Default::default() }),
+            collision_set: Some(CollisionSet { colliding: true, time_since_collision: 0.25, ..Default::default() }),
+            revolute_joint: Some(RevoluteJointData { linked_to_entity: other, multibody_link: true }),
+            chain_link: Some(ChainLinkData {
+                creation_animation: 0.5,
+                destruction_animation: 0.75,
+                expire: true,
+                next_link: Some(other),
+            }),
+            shifter: Some(ShifterData { target_room: Some(other), target_entity: None, shifting: true, sensing: false }),
+            jump: Some(Jump { cooldown: 0.125 }),
+            room_animation: Some(Animation::new(32)),
+            spawn_point: Some(SpawnPoint { kind: SpawnKind::Ball }),
+            room_link: Some(RoomLinkData { target_room: other }),
+            terrain_layer: Some(TerrainLayer::Foreground),
+            room_properties: Some(RoomProperties { name: "Cave".into(), background_color: Some([0.1, 0.2, 0.3]), ..Default::default() }),
+        };
+
+        SaveFile {
+            version: SAVE_VERSION,
+            level: Some("level.ron".into()),
+            grid: Some(Grid::default()),
+            entities: vec![
+                EntityRecord { marker: U64Marker(1), components },
+                EntityRecord { marker: other, components: Components::default() },
+            ],
+        }
+    }
+
+    #[test]
+    fn json_round_trip_keeps_every_component() {
+        let save_file = every_component();
+
+        let json = serialize_save(&save_file, SaveFormat::Json).unwrap();
+        let loaded = deserialize_save(json.as_bytes(), SaveFormat::Json).unwrap();
+
+        assert!(loaded.entities[0].components.room.is_some());
+        assert!(loaded.entities[1].components.room.is_none());
+
+        // Compare through RON, which keeps every component apart from JSON's handling of rooms
+        assert_eq!(serialize_save(&loaded, SaveFormat::Ron).unwrap(),
+                   serialize_save(&save_file, SaveFormat::Ron).unwrap());
+    }
+
+    #[test]
+    fn backups_keep_the_format_of_their_save() {
+        assert_eq!(SaveFormat::from_file_name("storage.json"), SaveFormat::Json);
+        assert_eq!(SaveFormat::from_file_name("storage.json.bak1"), SaveFormat::Json);
+        assert_eq!(SaveFormat::from_file_name("saves/storage.JSON.bak12"), SaveFormat::Json);
+        assert_eq!(SaveFormat::from_file_name("storage.ron.bak2"), SaveFormat::Ron);
+        assert_eq!(SaveFormat::from_file_name("storage.bak"), SaveFormat::Ron);
+        assert_eq!(SaveFormat::from_file_name("storage"), SaveFormat::Ron);
+    }
+
+    #[test]
+    fn json_saves_with_null_rooms_still_load_rooms() {
+        let contents = br#"{ "version": 3, "entities": [{ "marker": 1, "components": { "room": null } }] }"#;
+
+        let loaded = deserialize_save(contents, SaveFormat::Json).unwrap();
+
+        assert!(loaded.entities[0].components.room.is_some());
+    }
+
+    fn room_link_of(save_file: &SaveFile, marker: u64) -> Option<u64> {
+        save_file.entities.iter()
+            .find(|record| record.marker.0 == marker)
+            .and_then(|record| record.components.room_link.as_ref())
+            .map(|room_link| room_link.target_room.0)
+    }
+
+    #[test]
+    fn version_0_saves_are_migrated_to_the_current_version() {
+        let contents = b"[
+            EntityData(
+                marker: U64Marker(5),
+                components: (Some(Position(x: 0, y: 0)), Some(Size(width: 100, height: 100)), None,
+                    Some(Room), None, None, None, None, None, None, None, None, None, None, None),
+            ),
+            EntityData(
+                marker: U64Marker(6),
+                components: (Some(Position(x: 50, y: 50)), None, Some(Shape(size: 10, class: Ball)),
+                    None, Some(InRoom(room_entity: 0)), None, Some(Velocity(x: 0, y: 0)), None, None,
+                    None, None, None, None, None, None),
+            ),
+            EntityData(
+                marker: U64Marker(7),
+                components: (Some(Position(x: 200, y: 0)), Some(Size(width: 100, height: 100)), None,
+                    Some(Room), None, None, None, None, None, None, None, None, None, None, None),
+            ),
+        ]";
+
+        let loaded = deserialize_save(contents, SaveFormat::Ron).unwrap();
+
+        assert_eq!(loaded.version, SAVE_VERSION);
+
+        // Raw entity indices become the markers of the entities at those positions
+        let in_room = loaded.entities[1].components.in_room.as_ref().unwrap();
+        assert_eq!(in_room.room_entity.0, 5);
+
+        // Rooms are linked to the next room in the file, wrapping around
+        assert_eq!(room_link_of(&loaded, 5), Some(7));
+        assert_eq!(room_link_of(&loaded, 6), None);
+        assert_eq!(room_link_of(&loaded, 7), Some(5));
+    }
+
+    #[test]
+    fn default_storage_is_migrated_to_the_current_version() {
+        let loaded = deserialize_save(include_bytes!("../default-storage.ron"), SaveFormat::Ron).unwrap();
+
+        assert_eq!(loaded.version, SAVE_VERSION);
+        assert_eq!(room_link_of(&loaded, 11262), Some(11266));
+        assert_eq!(room_link_of(&loaded, 11266), Some(11262));
+
+        let rooms = loaded.entities.iter().filter(|record| record.components.room.is_some()).count();
+        let links = loaded.entities.iter().filter(|record| record.components.room_link.is_some()).count();
+        assert_eq!((rooms, links), (2, 2));
+    }
+}
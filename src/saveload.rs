@@ -1,68 +1,717 @@
 extern crate specs;
 extern crate ron;
 
-use specs::saveload::{DeserializeComponents, SerializeComponents, U64Marker, U64MarkerAllocator};
-use specs::prelude::{System, Entities, ReadStorage, Join, Write, WriteStorage};
+use specs::saveload::{U64Marker, U64MarkerAllocator, Marker, MarkerAllocator, IntoSerialize, FromDeserialize};
+use specs::prelude::{World, Entity, Component, System, RunNow, Entities, ReadStorage, ReadExpect, Join, Write, WriteStorage, WriteExpect, LazyUpdate};
+use specs::world::EntitiesRes;
 use specs::storage::NullStorage;
+use std::collections::HashSet;
 
-use error::Error;
-use draw::{Position, Size, Shape};
-use shift::Shifter;
+use error::{Error, GameError};
+use draw::{Position, PreviousPosition, Size, Shape, ShapeClass, TerrainLayer};
+use shift::{Shifter, RoomLink};
 use animate::{Animation, RoomAnimation};
-use physics::{Room, InRoom, Force, Velocity, CollisionSet, RevoluteJoint, Aim};
+use physics::{Room, RoomProperties, InRoom, Force, Velocity, CollisionSet, RevoluteJoint, Aim};
 use input::PlayerController;
 use control::{Jump, ChainLink};
+use edit::{SpawnPoint, Grid};
+use saveformat::{SaveFile, SaveFormat, EntityRecord, Components, SAVE_VERSION, serialize_save, deserialize_save};
+
+/// Register every component and resource that is part of a save file, or that loading resets.
+pub fn register_components(world: &mut World) {
+    world.register::<Position>();
+    world.register::<Size>();
+    world.register::<Shape>();
+    world.register::<Room>();
+    world.register::<InRoom>();
+    world.register::<PlayerController>();
+    world.register::<Velocity>();
+    world.register::<Force>();
+    world.register::<Aim>();
+    world.register::<CollisionSet>();
+    world.register::<RevoluteJoint>();
+    world.register::<ChainLink>();
+    world.register::<Shifter>();
+    world.register::<Jump>();
+    world.register::<Animation<RoomAnimation>>();
+    world.register::<SpawnPoint>();
+    world.register::<RoomLink>();
+    world.register::<TerrainLayer>();
+    world.register::<RoomProperties>();
+    world.register::<U64Marker>();
+    world.register::<PreviousPosition>();
+
+    world.add_resource(U64MarkerAllocator::new());
+    world.add_resource(SaveLoadErrors::default());
+    world.add_resource(CurrentLevel::default());
+    world.add_resource(Grid::default());
+}
+
+/// The level file the current session is built upon, if any.
+#[derive(Debug, Clone, Default)]
+pub struct CurrentLevel {
+    pub file_name: Option<String>,
+    /// Whether the level was edited since it was last loaded or saved
+    pub modified: bool,
+}
+
+/// Which entities a save includes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveScope {
+    /// Every entity, in a self-contained file
+    Everything,
+    /// Only authored content: rooms, terrain and spawn points, i.e. entities that never move
+    Level,
+    /// Only dynamic entities, which refer to the rooms of the given level file; the save stores
+    /// the level's path relative to itself
+    Session { level: String },
+}
+
+impl SaveScope {
+    fn includes(&self, dynamic: bool) -> bool {
+        match *self {
+            SaveScope::Everything => true,
+            SaveScope::Level => !dynamic,
+            SaveScope::Session { .. } => dynamic,
+        }
+    }
+
+    fn level(&self) -> Option<String> {
+        match *self {
+            SaveScope::Session { ref level } => Some(level.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether the save holds the level-wide settings, such as the editor grid.
+    fn includes_settings(&self) -> bool {
+        match *self {
+            SaveScope::Session { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Find the marker to save in place of an `entity` referenced by `component`.
+///
+/// Components that refer to other entities implement `IntoSerialize`/`FromDeserialize` with
+/// these, so that references survive entities being re-created with different ids on load.
+pub fn marker_of<M, F>(component: &str, entity: Entity, ids: &mut F) -> Result<M, GameError>
+    where F: FnMut(Entity) -> Option<M>
+{
+    ids(entity).ok_or_else(|| GameError::DanglingReference { component: component.into() })
+}
+
+pub fn optional_marker_of<M, F>(component: &str, entity: Option<Entity>, ids: &mut F)
+    -> Result<Option<M>, GameError>
+    where F: FnMut(Entity) -> Option<M>
+{
+    match entity {
+        Some(entity) => marker_of(component, entity, ids).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Find the entity a loaded `marker` refers to; the reverse of `marker_of`.
+pub fn entity_of<M, F>(component: &str, marker: M, ids: &mut F) -> Result<Entity, GameError>
+    where F: FnMut(M) -> Option<Entity>
+{
+    ids(marker).ok_or_else(|| GameError::DanglingReference { component: component.into() })
+}
+
+pub fn optional_entity_of<M, F>(component: &str, marker: Option<M>, ids: &mut F)
+    -> Result<Option<Entity>, GameError>
+    where F: FnMut(M) -> Option<Entity>
+{
+    match marker {
+        Some(marker) => entity_of(component, marker, ids).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Errors reported by the save and load systems since they were last taken.
+#[derive(Debug, Default)]
+pub struct SaveLoadErrors {
+    pub errors: Vec<GameError>,
+    /// Problems in the last loaded file that loading worked around, such as settings out of
+    /// range; `validate_world` reports them
+    pub load_warnings: Vec<String>,
+}
+
+impl SaveLoadErrors {
+    /// Clear the reported errors, returning the first one if there were any.
+    pub fn take_result(&mut self) -> Result<(), Error> {
+        let mut errors = self.errors.drain(..);
+
+        match errors.next() {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+type SaveData<'a> = (
+    Entities<'a>,
+    ReadStorage<'a, Position>,
+    ReadStorage<'a, Size>,
+    ReadStorage<'a, Shape>,
+    ReadStorage<'a, Room>,
+    ReadStorage<'a, InRoom>,
+    ReadStorage<'a, PlayerController>,
+    ReadStorage<'a, Velocity>,
+    ReadStorage<'a, Force>,
+    ReadStorage<'a, Aim>,
+    ReadStorage<'a, CollisionSet>,
+    ReadStorage<'a, RevoluteJoint>,
+    ReadStorage<'a, ChainLink>,
+    ReadStorage<'a, Shifter>,
+    ReadStorage<'a, Jump>,
+    ReadStorage<'a, Animation<RoomAnimation>>,
+    ReadStorage<'a, SpawnPoint>,
+    ReadStorage<'a, RoomLink>,
+    ReadStorage<'a, TerrainLayer>,
+    ReadStorage<'a, RoomProperties>,
+    ReadStorage<'a, U64Marker>,
+);
+
+/// Convert `entity`'s `C` component, if it has one, into its saved form.
+fn save_component<C, F>(storage: &ReadStorage<C>, entity: Entity, ids: &mut F)
+    -> Result<Option<C::Data>, Error>
+    where C: IntoSerialize<U64Marker>, Error: From<C::Error>, F: FnMut(Entity) -> Option<U64Marker>
+{
+    match storage.get(entity) {
+        Some(component) => Ok(Some(IntoSerialize::<U64Marker>::into(component, |entity| ids(entity))?)),
+        None => Ok(None),
+    }
+}
+
+/// The saved form of `entity` and its components.
+fn entity_record(&(_, ref positions, ref sizes, ref shapes, ref rooms, ref in_rooms,
+    ref player_controllers, ref velocities, ref forces, ref aims, ref collision_sets,
+    ref revolute_joints, ref chain_links, ref shifters, ref jumps, ref animations, ref spawn_points,
+    ref room_links, ref terrain_layers, ref room_properties, ref markers): &SaveData, entity: Entity, marker: &U64Marker) -> Result<EntityRecord, Error>
+{
+    let mut ids = |entity| markers.get(entity).cloned();
+
+    Ok(EntityRecord {
+        marker: marker.clone(),
+        components: Components {
+            position: save_component(positions, entity, &mut ids)?,
+            size: save_component(sizes, entity, &mut ids)?,
+            shape: save_component(shapes, entity, &mut ids)?,
+            room: save_component(rooms, entity, &mut ids)?,
+            in_room: save_component(in_rooms, entity, &mut ids)?,
+            player_controller: save_component(player_controllers, entity, &mut ids)?,
+            velocity: save_component(velocities, entity, &mut ids)?,
+            force: save_component(forces, entity, &mut ids)?,
+            aim: save_component(aims, entity, &mut ids)?,
+            collision_set: save_component(collision_sets, entity, &mut ids)?,
+            revolute_joint: save_component(revolute_joints, entity, &mut ids)?,
+            chain_link: save_component(chain_links, entity, &mut ids)?,
+            shifter: save_component(shifters, entity, &mut ids)?,
+            jump: save_component(jumps, entity, &mut ids)?,
+            room_animation: save_component(animations, entity, &mut ids)?,
+            spawn_point: save_component(spawn_points, entity, &mut ids)?,
+            room_link: save_component(room_links, entity, &mut ids)?,
+            terrain_layer: save_component(terrain_layers, entity, &mut ids)?,
+            room_properties: save_component(room_properties, entity, &mut ids)?,
+        },
+    })
+}
+
+fn serialize_world(data: SaveData, grid: &Grid, scope: &SaveScope, file_name: &str) -> Result<String, Error> {
+    let (ref entities, _, _, _, _, _, _, ref velocities, _, _, _, _, _, _, _, _, _, _, _, _, ref markers) = data;
+    let mut records = Vec::new();
+
+    for (entity, marker) in (&**entities, markers).join() {
+        if !scope.includes(velocities.get(entity).is_some()) {
+            continue;
+        }
+
+        records.push(entity_record(&data, entity, marker)?);
+    }
+
+    let grid = if scope.includes_settings() { Some(*grid) } else { None };
+
+    let level = scope.level().map(|level| relative_to_file(&level, file_name));
+
+    serialize_save(&SaveFile { version: SAVE_VERSION, level, grid, entities: records },
+                   SaveFormat::from_file_name(file_name))
+}
+
+type LoadData<'a> = (
+    Entities<'a>,
+    Write<'a, U64MarkerAllocator>,
+    WriteStorage<'a, Position>,
+    WriteStorage<'a, Size>,
+    WriteStorage<'a, Shape>,
+    WriteStorage<'a, Room>,
+    WriteStorage<'a, InRoom>,
+    WriteStorage<'a, PlayerController>,
+    WriteStorage<'a, Velocity>,
+    WriteStorage<'a, Force>,
+    WriteStorage<'a, Aim>,
+    WriteStorage<'a, CollisionSet>,
+    WriteStorage<'a, RevoluteJoint>,
+    WriteStorage<'a, ChainLink>,
+    WriteStorage<'a, Shifter>,
+    WriteStorage<'a, Jump>,
+    WriteStorage<'a, Animation<RoomAnimation>>,
+    WriteStorage<'a, SpawnPoint>,
+    WriteStorage<'a, RoomLink>,
+    WriteStorage<'a, TerrainLayer>,
+    WriteStorage<'a, RoomProperties>,
+    WriteStorage<'a, U64Marker>,
+    WriteStorage<'a, PreviousPosition>,
+);
+
+/// Find the live entity with `marker`, creating one if there is none. Unlike the allocator's
+/// `retrieve_entity`, this does not return entities that were deleted since they were marked.
+fn entity_for_marker(marker: U64Marker, allocator: &mut U64MarkerAllocator,
+    markers: &mut WriteStorage<U64Marker>, entities: &EntitiesRes) -> Entity
+{
+    let entity = allocator.retrieve_entity(marker, markers, entities);
+
+    if entities.is_alive(entity) {
+        return entity;
+    }
+
+    let entity = entities.create();
+    let marker = allocator.allocate(entity, Some(marker.id()));
+    markers.insert(entity, marker)
+        .expect("Newly created entities are always alive");
+
+    entity
+}
+
+/// Convert a saved component back, and add it to `entity`.
+fn load_component<C, F>(storage: &mut WriteStorage<C>, entity: Entity, data: Option<C::Data>, ids: &mut F)
+    -> Result<(), Error>
+    where C: FromDeserialize<U64Marker> + Component, Error: From<C::Error>,
+          F: FnMut(U64Marker) -> Option<Entity>
+{
+    if let Some(data) = data {
+        let component = FromDeserialize::<U64Marker>::from(data, |marker| ids(marker))?;
+        storage.insert(entity, component)?;
+    }
+
+    Ok(())
+}
+
+fn deserialize_world((entities, mut allocator, mut positions, mut sizes, mut shapes, mut rooms,
+    mut in_rooms, mut player_controllers, mut velocities, mut forces, mut aims, mut collision_sets,
+    mut revolute_joints, mut chain_links, mut shifters, mut jumps, mut animations,
+    mut spawn_points, mut room_links, mut terrain_layers, mut room_properties, mut markers,
+    mut previous_positions): LoadData, save_files: &[SaveFile]) -> Result<(), Error>
+{
+    // References must be to an entity in one of the files, or to one already in the world, e.g.
+    // the rooms around entities restored by an undo
+    let known_markers: HashSet<u64> = save_files.iter()
+        .flat_map(|save_file| save_file.entities.iter().map(|record| record.marker.id()))
+        .chain((&*entities, &markers).join().map(|(_entity, marker)| marker.id()))
+        .collect();
+
+    // Entities referenced before their own record is reached are created early, and picked up
+    // again by their marker later
+    let mut ids = |marker: U64Marker| if known_markers.contains(&marker.id()) {
+        Some(entity_for_marker(marker, &mut allocator, &mut markers, &entities))
+    } else {
+        None
+    };
+
+    let records = save_files.iter()
+        .flat_map(|save_file| save_file.entities.iter().cloned());
+
+    for EntityRecord { marker, components } in records {
+        let entity = ids(marker).expect("Marker allocator always returns an entity");
+
+        load_component(&mut positions, entity, components.position, &mut ids)?;
+        load_component(&mut sizes, entity, components.size, &mut ids)?;
+        load_component(&mut shapes, entity, components.shape, &mut ids)?;
+        load_component(&mut rooms, entity, components.room, &mut ids)?;
+        load_component(&mut in_rooms, entity, components.in_room, &mut ids)?;
+        load_component(&mut player_controllers, entity, components.player_controller, &mut ids)?;
+        load_component(&mut velocities, entity, components.velocity, &mut ids)?;
+        load_component(&mut forces, entity, components.force, &mut ids)?;
+        load_component(&mut aims, entity, components.aim, &mut ids)?;
+        load_component(&mut collision_sets, entity, components.collision_set, &mut ids)?;
+        load_component(&mut revolute_joints, entity, components.revolute_joint, &mut ids)?;
+        load_component(&mut chain_links, entity, components.chain_link, &mut ids)?;
+        load_component(&mut shifters, entity, components.shifter, &mut ids)?;
+        load_component(&mut jumps, entity, components.jump, &mut ids)?;
+        load_component(&mut animations, entity, components.room_animation, &mut ids)?;
+        load_component(&mut spawn_points, entity, components.spawn_point, &mut ids)?;
+        load_component(&mut room_links, entity, components.room_link, &mut ids)?;
+        load_component(&mut terrain_layers, entity, components.terrain_layer, &mut ids)?;
+        load_component(&mut room_properties, entity, components.room_properties, &mut ids)?;
+
+        // A loaded entity is drawn where it was loaded, not blended from where it was before
+        previous_positions.remove(entity);
+    }
+
+    Ok(())
+}
+
+/// Deserializes into whatever world it is run on, and keeps the result.
+struct DeserializeWorld<'c> {
+    save_files: &'c [SaveFile],
+    result: Result<(), Error>,
+}
+
+impl <'a, 'c> System<'a> for DeserializeWorld<'c> {
+    type SystemData = LoadData<'a>;
+
+    fn run(&mut self, data: Self::SystemData) {
+        self.result = deserialize_world(data, self.save_files);
+    }
+}
+
+/// Deserialize `save_files` into a scratch world, so that a broken file is detected before it
+/// leaves half of its entities in the real one.
+fn check_world(save_files: &[SaveFile]) -> Result<(), Error> {
+    let mut world = World::new();
+    register_components(&mut world);
+
+    let mut deserialize = DeserializeWorld { save_files, result: Ok(()) };
+    deserialize.run_now(&world.res);
+
+    deserialize.result
+}
+
+/// Saves the given entities into records, e.g. for the editor's undo history.
+struct SnapshotEntities<'c> {
+    entities: &'c [Entity],
+    result: Result<Vec<EntityRecord>, Error>,
+}
+
+impl <'a, 'c> System<'a> for SnapshotEntities<'c> {
+    type SystemData = SaveData<'a>;
+
+    fn run(&mut self, data: Self::SystemData) {
+        let mut records = Vec::with_capacity(self.entities.len());
+
+        for &entity in self.entities {
+            if let Some(marker) = data.20.get(entity) {
+                match entity_record(&data, entity, marker) {
+                    Ok(record) => records.push(record),
+                    Err(error) => {
+                        self.result = Err(error);
+                        return;
+                    },
+                }
+            }
+        }
+
+        self.result = Ok(records);
+    }
+}
+
+/// The saved state of `entities`; entities without a marker are skipped.
+pub fn snapshot_entities(world: &World, entities: &[Entity]) -> Result<Vec<EntityRecord>, Error> {
+    let mut snapshot = SnapshotEntities { entities, result: Ok(Vec::new()) };
+    snapshot.run_now(&world.res);
+
+    snapshot.result
+}
+
+/// Strips the saved components from the entities that will be restored, and deletes the ones
+/// that will not.
+struct ClearEntities<'c> {
+    replaced: &'c [EntityRecord],
+    records: &'c [EntityRecord],
+}
+
+impl <'a, 'c> System<'a> for ClearEntities<'c> {
+    type SystemData = (LoadData<'a>, WriteStorage<'a, DestroyEntity>);
+
+    fn run(&mut self, ((entities, _allocator, mut positions, mut sizes, mut shapes, mut rooms,
+        mut in_rooms, mut player_controllers, mut velocities, mut forces, mut aims, mut collision_sets,
+        mut revolute_joints, mut chain_links, mut shifters, mut jumps, mut animations,
+        mut spawn_points, mut room_links, mut terrain_layers, mut room_properties, markers, _previous_positions),
+        mut destroy_entities): Self::SystemData)
+    {
+        let has_marker = |records: &[EntityRecord], marker: &U64Marker|
+            records.iter().any(|record| record.marker == *marker);
+
+        for (entity, marker) in (&*entities, &markers).join() {
+            if has_marker(self.records, marker) {
+                positions.remove(entity);
+                sizes.remove(entity);
+                shapes.remove(entity);
+                rooms.remove(entity);
+                in_rooms.remove(entity);
+                player_controllers.remove(entity);
+                velocities.remove(entity);
+                forces.remove(entity);
+                aims.remove(entity);
+                collision_sets.remove(entity);
+                revolute_joints.remove(entity);
+                chain_links.remove(entity);
+                shifters.remove(entity);
+                jumps.remove(entity);
+                animations.remove(entity);
+                spawn_points.remove(entity);
+                room_links.remove(entity);
+                terrain_layers.remove(entity);
+                room_properties.remove(entity);
+                destroy_entities.remove(entity);
+            } else if has_marker(self.replaced, marker) {
+                entities.delete(entity)
+                    .expect("Joined entities are always alive");
+            }
+        }
+    }
+}
+
+/// Replace the entities saved in `replaced` with the state saved in `records`: entities in both
+/// keep their identity and get the recorded components, entities only in `replaced` are deleted,
+/// and entities only in `records` are created with their recorded markers.
+pub fn restore_entities(world: &World, replaced: &[EntityRecord], records: &[EntityRecord]) -> Result<(), Error> {
+    ClearEntities { replaced, records }.run_now(&world.res);
+
+    let save_files = [SaveFile { version: SAVE_VERSION, level: None, grid: None, entities: records.to_vec() }];
+    let mut deserialize = DeserializeWorld { save_files: &save_files, result: Ok(()) };
+    deserialize.run_now(&world.res);
+
+    deserialize.result
+}
+
+/// Parse a save, preceded by the level it is built upon if it is a session. The session's level
+/// path is resolved, so that it can be used from the current directory.
+fn parse_save(file_name: &str, contents: &[u8]) -> Result<Vec<SaveFile>, GameError> {
+    let mut save_file = deserialize_save(contents, SaveFormat::from_file_name(file_name))
+        .map_err(|error| invalid_save_error(file_name, error))?;
+
+    let level_file_name = match save_file.level {
+        Some(ref level_file_name) => resolve_relative(level_file_name, file_name),
+        None => return Ok(vec![save_file]),
+    };
+
+    save_file.level = Some(level_file_name.clone());
+
+    let level_contents = read_file(&level_file_name)
+        .map_err(|error| load_error(&level_file_name, error))?;
+    let level = deserialize_save(&level_contents, SaveFormat::from_file_name(&level_file_name))
+        .map_err(|error| load_error(&level_file_name, error))?;
+
+    if level.level.is_some() {
+        return Err(load_error(file_name, format_err!(
+            "level '{}' is a session, not a level", level_file_name)));
+    }
+
+    Ok(vec![level, save_file])
+}
+
+fn load_error<E: Into<Error>>(file_name: &str, cause: E) -> GameError {
+    GameError::LoadError { file_name: file_name.into(), cause: cause.into().to_string() }
+}
+
+/// An error in the contents of `file_name`, as opposed to one in reading it or the files it
+/// refers to.
+fn invalid_save_error<E: Into<Error>>(file_name: &str, cause: E) -> GameError {
+    GameError::InvalidSave { file_name: file_name.into(), cause: cause.into().to_string() }
+}
+
+fn save_error<E: Into<Error>>(file_name: &str, cause: E) -> GameError {
+    GameError::SaveError { file_name: file_name.into(), cause: cause.into().to_string() }
+}
+
+/// Check `contents` and add its entities to the world, and take the grid from its level; the
+/// world is left untouched if the check fails. Returns the level file the save is built upon, if
+/// any.
+fn load_checked(data: LoadData, grid: &mut Grid, warnings: &mut Vec<String>, file_name: &str, contents: &[u8])
+    -> Result<Option<String>, GameError>
+{
+    warnings.clear();
+
+    let save_files = parse_save(file_name, contents)?;
+
+    check_world(&save_files)
+        .map_err(|error| invalid_save_error(file_name, error))?;
+
+    deserialize_world(data, &save_files)
+        .map_err(|error| invalid_save_error(file_name, error))?;
+
+    let saved_grid = save_files.iter()
+        .filter_map(|save_file| save_file.grid)
+        .next()
+        .unwrap_or_default();
+
+    // Levels can come from other tools, which may write a grid the editor cannot work with
+    *grid = saved_grid.clamped();
+
+    if *grid != saved_grid {
+        warnings.push(format!("grid cell size {} is outside of the supported range, using {} instead",
+                              saved_grid.cell_size, grid.cell_size));
+    }
+
+    Ok(save_files.last().and_then(|save_file| save_file.level.clone()))
+}
+
+/// `path` relative to the directory of `file_name`, so that a file can refer to another one no
+/// matter where it is loaded from; see `resolve_relative`. Absolute paths are kept as they are.
+fn relative_to_file(path: &str, file_name: &str) -> String {
+    use ::std::path::{Path, PathBuf};
+
+    let current_dir = match ::std::env::current_dir() {
+        Ok(current_dir) => current_dir,
+        Err(_) => return path.into(),
+    };
+
+    if Path::new(path).is_absolute() {
+        return path.into();
+    }
+
+    let path = normalize_path(&current_dir.join(path));
+    let file = normalize_path(&current_dir.join(file_name));
+    let directory = file.parent().unwrap_or(&file);
+
+    let common = path.components().zip(directory.components())
+        .take_while(|&(component, directory_component)| component == directory_component)
+        .count();
+
+    // Nothing in common, e.g. on another drive
+    if common == 0 {
+        return path.to_string_lossy().into_owned();
+    }
+
+    let mut relative = PathBuf::new();
+
+    for _ in directory.components().skip(common) {
+        relative.push("..");
+    }
+
+    for component in path.components().skip(common) {
+        relative.push(component.as_os_str());
+    }
+
+    relative.to_string_lossy().into_owned()
+}
+
+/// Remove the `.` and `..` components of an absolute `path`, without looking at the file system.
+fn normalize_path(path: &::std::path::Path) -> ::std::path::PathBuf {
+    use ::std::path::{PathBuf, Component};
+
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => { normalized.pop(); },
+            component => normalized.push(component.as_os_str()),
+        }
+    }
+
+    normalized
+}
+
+/// Resolve a `path` found in `file_name`, which is relative to the file's directory.
+fn resolve_relative(path: &str, file_name: &str) -> String {
+    match ::std::path::Path::new(file_name).parent() {
+        Some(directory) => directory.join(path).to_string_lossy().into_owned(),
+        None => path.into(),
+    }
+}
+
+/// Name of the `backup`th most recent backup of `file_name`, starting from 1.
+pub fn backup_file_name(file_name: &str, backup: usize) -> String {
+    format!("{}.bak{}", file_name, backup)
+}
+
+/// Shift the existing backups of `file_name` by one, dropping the oldest, and make the current
+/// contents of `file_name` the most recent one.
+fn rotate_backups(file_name: &str, backups: usize) -> Result<(), ::std::io::Error> {
+    use ::std::fs;
+    use ::std::path::Path;
+
+    if backups == 0 || !Path::new(file_name).exists() {
+        return Ok(());
+    }
+
+    for backup in (1..backups).rev() {
+        let backup_file = backup_file_name(file_name, backup);
+
+        if Path::new(&backup_file).exists() {
+            fs::rename(&backup_file, backup_file_name(file_name, backup + 1))?;
+        }
+    }
+
+    // Copy rather than move, so that there is always a complete save at `file_name`
+    fs::copy(file_name, backup_file_name(file_name, 1))?;
+
+    Ok(())
+}
+
+/// Write `contents` to `file_name` atomically: the data is written to a temporary file first, so
+/// a crash in the middle of a save leaves the previous save intact.
+fn write_file(file_name: &str, contents: &str, backups: usize) -> Result<(), GameError> {
+    use ::std::fs::{self, File};
+    use ::std::io::Write;
+
+    let temp_file_name = format!("{}.tmp", file_name);
+
+    let write_temp_file = || -> Result<(), ::std::io::Error> {
+        let mut file = File::create(&temp_file_name)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    };
+
+    write_temp_file()
+        .map_err(|error| save_error(&temp_file_name, error))?;
+    rotate_backups(file_name, backups)
+        .map_err(|error| save_error(file_name, format_err!("cannot rotate backups: {}", error)))?;
+    fs::rename(&temp_file_name, file_name)
+        .map_err(|error| save_error(file_name, error))?;
+
+    Ok(())
+}
+
+fn read_file(file_name: &str) -> Result<Vec<u8>, ::std::io::Error> {
+    use ::std::fs::File;
+    use ::std::io::Read;
+
+    let mut file = File::open(file_name)?;
+    let mut file_contents = Vec::new();
+    file.read_to_end(&mut file_contents)?;
+
+    Ok(file_contents)
+}
 
 pub struct SaveWorld {
     pub file_name: String,
+    /// How many previous versions of the file to keep around
+    pub backups: usize,
+    pub scope: SaveScope,
 }
 
 impl <'a> System<'a> for SaveWorld {
-    type SystemData = (
-        Entities<'a>,
-        ReadStorage<'a, Position>,
-        ReadStorage<'a, Size>,
-        ReadStorage<'a, Shape>,
-        ReadStorage<'a, Room>,
-        ReadStorage<'a, InRoom>,
-        ReadStorage<'a, PlayerController>,
-        ReadStorage<'a, Velocity>,
-        ReadStorage<'a, Force>,
-        ReadStorage<'a, Aim>,
-        ReadStorage<'a, CollisionSet>,
-        ReadStorage<'a, RevoluteJoint>,
-        ReadStorage<'a, ChainLink>,
-        ReadStorage<'a, Shifter>,
-        ReadStorage<'a, Jump>,
-        ReadStorage<'a, Animation<RoomAnimation>>,
-        ReadStorage<'a, U64Marker>,
-    );
+    type SystemData = (SaveData<'a>, ReadExpect<'a, Grid>, WriteExpect<'a, SaveLoadErrors>);
 
-    fn run(&mut self, (entities, positions, sizes, shapes, rooms, in_rooms,
-        player_controllers, velocities, forces, aims, collision_sets,
-        revolute_joints, chain_links, shifters, jumps, animations, markers): Self::SystemData)
-    {
-        let mut serializer = ron::ser::Serializer::new(Some(Default::default()), true);
-        SerializeComponents::<Error, U64Marker>::serialize(
-            &(positions, sizes, shapes, rooms, in_rooms, player_controllers, velocities,
-              forces, aims, collision_sets, revolute_joints, chain_links, shifters, jumps, animations),
-            &entities,
-            &markers,
-            &mut serializer
-        ).unwrap_or_else(|e| {
-            // FIXME: handle this
-            eprintln!("Error: {}", e);
-        });
+    fn run(&mut self, (data, grid, mut save_load_errors): Self::SystemData) {
+        let result = serialize_world(data, &grid, &self.scope, &self.file_name)
+            .map_err(|error| save_error(&self.file_name, error))
+            .and_then(|file_contents| write_file(&self.file_name, &file_contents, self.backups));
 
-        let file_contents = serializer.into_output_string();
+        if let Err(error) = result {
+            save_load_errors.errors.push(error);
+        }
+    }
+}
 
-        use ::std::fs::File;
-        use ::std::io::Write;
+/// Like `SaveWorld`, but keeps the result in memory instead of writing it to a file.
+#[derive(Default)]
+pub struct SaveWorldToString {
+    pub contents: String,
+}
 
-        let mut file = File::create(&self.file_name)
-            .expect("Could not create save file.");
-        file.write_all(file_contents.as_bytes())
-            .expect("Could not write save file.");
+impl <'a> System<'a> for SaveWorldToString {
+    type SystemData = (SaveData<'a>, ReadExpect<'a, Grid>, WriteExpect<'a, SaveLoadErrors>);
+
+    fn run(&mut self, (data, grid, mut save_load_errors): Self::SystemData) {
+        match serialize_world(data, &grid, &SaveScope::Everything, "<memory>") {
+            Ok(contents) => self.contents = contents,
+            Err(error) => save_load_errors.errors.push(save_error("<memory>", error)),
+        }
     }
 }
 
@@ -73,66 +722,89 @@ pub struct LoadWorld {
 
 impl <'a> System<'a> for LoadWorld {
     type SystemData = (
-        Entities<'a>,
-        Write<'a, U64MarkerAllocator>,
-        WriteStorage<'a, Position>,
-        WriteStorage<'a, Size>,
-        WriteStorage<'a, Shape>,
-        WriteStorage<'a, Room>,
-        WriteStorage<'a, InRoom>,
-        WriteStorage<'a, PlayerController>,
-        WriteStorage<'a, Velocity>,
-        WriteStorage<'a, Force>,
-        WriteStorage<'a, Aim>,
-        WriteStorage<'a, CollisionSet>,
-        WriteStorage<'a, RevoluteJoint>,
-        WriteStorage<'a, ChainLink>,
-        WriteStorage<'a, Shifter>,
-        WriteStorage<'a, Jump>,
-        WriteStorage<'a, Animation<RoomAnimation>>,
-        WriteStorage<'a, U64Marker>,
+        LoadData<'a>,
+        WriteExpect<'a, SaveLoadErrors>,
+        WriteExpect<'a, CurrentLevel>,
+        WriteExpect<'a, Grid>,
     );
 
-    fn run(&mut self, (entities, mut allocator, positions, sizes, shapes, rooms, in_rooms, player_controllers,
-        velocities, forces, aims, collision_sets, revolute_joints, chain_links, shifters, jumps, animations, mut markers)
-    : Self::SystemData) {
-        use ::std::fs::File;
-        use ::std::io::Read;
-
-        let file_contents = {
-            // FIXME: Replace panic! and expect! with actual error handling/recovery
-            let mut file = match File::open(&self.file_name) {
-                Ok(file) => file,
-                Err(error) => {
-                    if error.kind() == ::std::io::ErrorKind::NotFound {
-                        eprintln!("Save file '{}' not found, loading from '{}' instead.",
-                                  self.file_name, self.default_storage);
-                        File::open(&self.default_storage)
-                            .expect("Could not open file.")
-                    } else {
-                        panic!("Could not open save file: {} ({})", self.file_name, error);
-                    }
-                },
-            };
-            let mut file_contents = Vec::new();
-            file.read_to_end(&mut file_contents)
-                .expect("Could not read file.");
-            file_contents
+    fn run(&mut self, (data, mut save_load_errors, mut current_level, mut grid): Self::SystemData) {
+        let mut file_name = &self.file_name;
+
+        let file_contents = match read_file(file_name) {
+            Err(ref error) if error.kind() == ::std::io::ErrorKind::NotFound => {
+                eprintln!("Save file '{}' not found, loading from '{}' instead.",
+                          self.file_name, self.default_storage);
+                file_name = &self.default_storage;
+                read_file(file_name)
+            },
+            result => result,
         };
 
-        let mut deserializer = ron::de::Deserializer::from_bytes(&file_contents)
-            .expect("Could not load"); // FIXME: handle error
+        let result = file_contents
+            .map_err(|error| load_error(file_name, error))
+            .and_then(|file_contents| load_checked(data, &mut grid, &mut save_load_errors.load_warnings,
+                                                   file_name, &file_contents));
+
+        match result {
+            Ok(level) => *current_level = CurrentLevel { file_name: level, modified: false },
+            Err(error) => save_load_errors.errors.push(error),
+        }
+    }
+}
+
+/// Like `LoadWorld`, but reads the world from a string, e.g. one produced by `SaveWorldToString`.
+pub struct LoadWorldFromString {
+    pub contents: String,
+    /// File the contents were read from, which a level path in them is relative to; `"<memory>"`
+    /// for contents that never were in a file, whose level paths are relative to the current
+    /// directory
+    pub file_name: String,
+}
+
+impl <'a> System<'a> for LoadWorldFromString {
+    type SystemData = (
+        LoadData<'a>,
+        WriteExpect<'a, SaveLoadErrors>,
+        WriteExpect<'a, CurrentLevel>,
+        WriteExpect<'a, Grid>,
+    );
+
+    fn run(&mut self, (data, mut save_load_errors, mut current_level, mut grid): Self::SystemData) {
+        let result = load_checked(data, &mut grid, &mut save_load_errors.load_warnings,
+                                  &self.file_name, self.contents.as_bytes());
+
+        match result {
+            Ok(level) => *current_level = CurrentLevel { file_name: level, modified: false },
+            Err(error) => save_load_errors.errors.push(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotRequest {
+    Save(u8),
+    Load(u8),
+}
+
+/// Numbered quicksave slots. Requests are carried out by the `Simulation` at the end of the tick,
+/// since loading replaces the whole world.
+#[derive(Debug)]
+pub struct SaveSlots {
+    pub current: u8,
+    pub request: Option<SlotRequest>,
+}
+
+impl SaveSlots {
+    pub fn new() -> Self {
+        SaveSlots {
+            current: 1,
+            request: None,
+        }
+    }
 
-        DeserializeComponents::<Error, _>::deserialize(
-            &mut (positions, sizes, shapes, rooms, in_rooms, player_controllers, velocities,
-                  forces, aims, collision_sets, revolute_joints, chain_links, shifters, jumps, animations),
-            &entities,
-            &mut markers,
-            &mut allocator,
-            &mut deserializer,
-        ).unwrap_or_else(|e| {
-            eprintln!("Error: {}", e); // FIXME: handle error
-        })
+    pub fn file_name(slot: u8) -> String {
+        format!("quicksave-{}.ron", slot)
     }
 }
 
@@ -146,9 +818,12 @@ impl <'a> System<'a> for ResetWorld {
     type SystemData = (
         Entities<'a>,
         WriteStorage<'a, DestroyEntity>,
+        WriteExpect<'a, CurrentLevel>,
     );
 
-    fn run(&mut self, (entities, mut destroy_entities): Self::SystemData) {
+    fn run(&mut self, (entities, mut destroy_entities, mut current_level): Self::SystemData) {
+        current_level.modified = true;
+
         for entity in entities.join() {
             destroy_entities.insert(entity, DestroyEntity)
                 .expect("Could not insert DestroyEntity component");
@@ -156,18 +831,142 @@ impl <'a> System<'a> for ResetWorld {
     }
 }
 
+/// Everything that goes away along with a set of destroyed entities.
+#[derive(Debug, Default)]
+pub struct Destruction {
+    pub destroyed: Vec<Entity>,
+    /// Entities joined to a destroyed one that survive it, e.g. a player holding a hook; they
+    /// must lose their joint
+    pub detached: Vec<Entity>,
+    /// Surviving rooms linked to a destroyed room; they must lose their link
+    pub unlinked: Vec<Entity>,
+}
+
+/// Find the entities destroyed along with `roots`: everything inside a destroyed room, and chain
+/// links joined to a destroyed entity, transitively.
+pub fn destruction_cascade(roots: &[Entity], entities: &EntitiesRes, in_rooms: &ReadStorage<InRoom>,
+    revolute_joints: &ReadStorage<RevoluteJoint>, shapes: &ReadStorage<Shape>,
+    room_links: &ReadStorage<RoomLink>) -> Destruction
+{
+    let mut destruction = Destruction { destroyed: roots.to_vec(), detached: Vec::new(), unlinked: Vec::new() };
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for (entity, in_room) in (entities, in_rooms).join() {
+            if destruction.destroyed.contains(&in_room.room_entity) && !destruction.destroyed.contains(&entity) {
+                destruction.destroyed.push(entity);
+                changed = true;
+            }
+        }
+
+        for (entity, revolute_joint) in (entities, revolute_joints).join() {
+            if !destruction.destroyed.contains(&revolute_joint.linked_to_entity) ||
+                destruction.destroyed.contains(&entity)
+            {
+                continue;
+            }
+
+            let is_chain_link = shapes.get(entity)
+                .map_or(false, |shape| shape.class == ShapeClass::ChainLink);
+
+            if is_chain_link {
+                destruction.destroyed.push(entity);
+                changed = true;
+            } else if !destruction.detached.contains(&entity) {
+                destruction.detached.push(entity);
+            }
+        }
+    }
+
+    destruction.detached.retain(|entity| !destruction.destroyed.contains(entity));
+
+    for (entity, room_link) in (entities, room_links).join() {
+        if destruction.destroyed.contains(&room_link.target_room) && !destruction.destroyed.contains(&entity) {
+            destruction.unlinked.push(entity);
+        }
+    }
+
+    destruction
+}
+
 pub struct DestroyEntities;
 
 impl <'a> System<'a> for DestroyEntities {
     type SystemData = (
         Entities<'a>,
         ReadStorage<'a, DestroyEntity>,
+        ReadStorage<'a, InRoom>,
+        ReadStorage<'a, RevoluteJoint>,
+        ReadStorage<'a, Shape>,
+        ReadStorage<'a, RoomLink>,
+        ReadExpect<'a, LazyUpdate>,
     );
 
-    fn run(&mut self, (entities, destroy_entities): Self::SystemData) {
-        for (entity, _destroy_entity) in (&*entities, &destroy_entities).join() {
+    fn run(&mut self, (entities, destroy_entities, in_rooms, revolute_joints, shapes, room_links,
+        lazy_update): Self::SystemData)
+    {
+        let roots: Vec<Entity> = (&*entities, &destroy_entities).join()
+            .map(|(entity, _destroy_entity)| entity)
+            .collect();
+
+        if roots.is_empty() {
+            return;
+        }
+
+        let destruction = destruction_cascade(&roots, &entities, &in_rooms, &revolute_joints, &shapes, &room_links);
+
+        for entity in destruction.destroyed {
             entities.delete(entity)
-                .expect("Error deleting entity during world reset");
+                .expect("Error deleting destroyed entity");
+        }
+
+        for entity in destruction.detached {
+            lazy_update.remove::<RevoluteJoint>(entity);
+            lazy_update.remove::<ChainLink>(entity);
+        }
+
+        for entity in destruction.unlinked {
+            lazy_update.remove::<RoomLink>(entity);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use specs::saveload::U64Marker;
+    use super::{relative_to_file, resolve_relative, check_world};
+    use saveformat::{SaveFile, EntityRecord, Components, SAVE_VERSION};
+    use physics::InRoomData;
+
+    #[test]
+    fn references_to_missing_entities_are_rejected() {
+        let in_room = EntityRecord {
+            marker: U64Marker(1),
+            components: Components {
+                in_room: Some(InRoomData { room_entity: U64Marker(2) }),
+                ..Components::default()
+            },
+        };
+
+        let save_file = SaveFile { version: SAVE_VERSION, level: None, grid: None, entities: vec![in_room.clone()] };
+        assert!(check_world(&[save_file]).is_err());
+
+        let room = EntityRecord { marker: U64Marker(2), components: Components::default() };
+        let save_file = SaveFile { version: SAVE_VERSION, level: None, grid: None, entities: vec![in_room, room] };
+        assert!(check_world(&[save_file]).is_ok());
+    }
+
+    #[test]
+    fn session_levels_are_found_relative_to_the_session() {
+        let level = relative_to_file("levels/tower.ron", "saves/storage.ron");
+        assert_eq!(Path::new(&level), Path::new("../levels/tower.ron"));
+        assert_eq!(Path::new(&resolve_relative(&level, "saves/storage.ron")), Path::new("saves/../levels/tower.ron"));
+
+        let level = relative_to_file("levels/tower.ron", "storage.ron");
+        assert_eq!(Path::new(&level), Path::new("levels/tower.ron"));
+        assert_eq!(Path::new(&resolve_relative(&level, "storage.ron")), Path::new("levels/tower.ron"));
+    }
+}
@@ -4,38 +4,122 @@
 /// another at the same (or similar) position.
 ///
 /// Overview:
+/// * Rooms are interconnected by the links that level designers draw between them; a room's
+///   `RoomLink` points to the room that objects inside it shift to
 /// * All entities capable of phase-shifting have a Shifter component
 ///   * Every update, the TrackShiftTarget figures out the target room, if there is one
 /// * ...
 
-use specs::prelude::{System, DenseVecStorage, Entities, ReadStorage, WriteStorage, Join};
-use specs::world::{Index, EntitiesRes};
+use specs::prelude::{System, DenseVecStorage, Entity, Entities, ReadStorage, WriteStorage, Join};
+use specs::saveload::{Marker, IntoSerialize, FromDeserialize};
+use specs::world::EntitiesRes;
 
 use physics::{Room, InRoom};
+use draw::PreviousPosition;
 use input::PlayerController;
+use saveload::{marker_of, entity_of, optional_marker_of, optional_entity_of};
+use error::GameError;
 
-#[derive(Component, Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
 #[storage(DenseVecStorage)]
 pub struct Shifter {
-    pub target_room: Option<Index>,
-    pub target_entity: Option<Index>,
+    pub target_room: Option<Entity>,
+    pub target_entity: Option<Entity>,
     pub shifting: bool,
     pub sensing: bool,
 }
 
+/// Saved form of `Shifter`, with the targets referenced by their markers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "Shifter")]
+pub struct ShifterData<M> {
+    pub target_room: Option<M>,
+    pub target_entity: Option<M>,
+    pub shifting: bool,
+    pub sensing: bool,
+}
 
-pub struct TrackShiftTarget;
+impl <M: Marker> IntoSerialize<M> for Shifter {
+    type Data = ShifterData<M>;
+    type Error = GameError;
+
+    fn into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+        where F: FnMut(Entity) -> Option<M>
+    {
+        Ok(ShifterData {
+            target_room: optional_marker_of("Shifter", self.target_room, &mut ids)?,
+            target_entity: optional_marker_of("Shifter", self.target_entity, &mut ids)?,
+            shifting: self.shifting,
+            sensing: self.sensing,
+        })
+    }
+}
 
-fn get_next_room<'a>(current_room: Index, entities: &EntitiesRes, rooms: &ReadStorage<'a, Room>) -> Option<Index> {
-    let iteration1 = (entities, rooms).join();
-    let iteration2 = (entities, rooms).join();
+impl <M: Marker> FromDeserialize<M> for Shifter {
+    type Data = ShifterData<M>;
+    type Error = GameError;
+
+    fn from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+        where F: FnMut(M) -> Option<Entity>
+    {
+        Ok(Shifter {
+            target_room: optional_entity_of("Shifter", data.target_room, &mut ids)?,
+            target_entity: optional_entity_of("Shifter", data.target_entity, &mut ids)?,
+            shifting: data.shifting,
+            sensing: data.sensing,
+        })
+    }
+}
 
-    let next_room = iteration1.chain(iteration2)
-        .map(|(entity, _room)| entity.id())
-        .skip_while(|room_index| current_room != *room_index)
-        .nth(1);
+/// Part of a room: the room that objects inside it phase shift to.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+#[storage(DenseVecStorage)]
+pub struct RoomLink {
+    pub target_room: Entity,
+}
 
-    next_room
+/// Saved form of `RoomLink`, with the target referenced by its marker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename = "RoomLink")]
+pub struct RoomLinkData<M> {
+    pub target_room: M,
+}
+
+impl <M: Marker> IntoSerialize<M> for RoomLink {
+    type Data = RoomLinkData<M>;
+    type Error = GameError;
+
+    fn into<F>(&self, mut ids: F) -> Result<Self::Data, Self::Error>
+        where F: FnMut(Entity) -> Option<M>
+    {
+        Ok(RoomLinkData {
+            target_room: marker_of("RoomLink", self.target_room, &mut ids)?,
+        })
+    }
+}
+
+impl <M: Marker> FromDeserialize<M> for RoomLink {
+    type Data = RoomLinkData<M>;
+    type Error = GameError;
+
+    fn from<F>(data: Self::Data, mut ids: F) -> Result<Self, Self::Error>
+        where F: FnMut(M) -> Option<Entity>
+    {
+        Ok(RoomLink {
+            target_room: entity_of("RoomLink", data.target_room, &mut ids)?,
+        })
+    }
+}
+
+pub struct TrackShiftTarget;
+
+/// The room that objects in `current_room` shift to, if it is linked to one that still exists.
+fn get_linked_room(current_room: Entity, entities: &EntitiesRes, rooms: &ReadStorage<Room>,
+    room_links: &ReadStorage<RoomLink>) -> Option<Entity>
+{
+    room_links.get(current_room)
+        .map(|room_link| room_link.target_room)
+        .filter(|&target_room| entities.is_alive(target_room) && rooms.get(target_room).is_some())
 }
 
 impl <'a> System<'a> for TrackShiftTarget {
@@ -44,12 +128,12 @@ impl <'a> System<'a> for TrackShiftTarget {
         WriteStorage<'a, Shifter>,
         ReadStorage<'a, InRoom>,
         ReadStorage<'a, Room>,
+        ReadStorage<'a, RoomLink>,
     );
 
-    fn run(&mut self, (entities, mut shifters, in_rooms, rooms): Self::SystemData) {
-        // Figure out the target room. Currently, it's just the next room in the Room storage.
+    fn run(&mut self, (entities, mut shifters, in_rooms, rooms, room_links): Self::SystemData) {
         for (_entity, mut shifter, in_room) in (&*entities, &mut shifters, &in_rooms).join() {
-            shifter.target_room = get_next_room(in_room.room_entity, &*entities, &rooms);
+            shifter.target_room = get_linked_room(in_room.room_entity, &*entities, &rooms, &room_links);
         }
     }
 }
@@ -83,15 +167,19 @@ impl <'a> System<'a> for PhaseShift {
         Entities<'a>,
         WriteStorage<'a, Shifter>,
         WriteStorage<'a, InRoom>,
+        WriteStorage<'a, PreviousPosition>,
     );
 
-    fn run(&mut self, (entities, mut shifters, mut in_rooms): Self::SystemData) {
-        for (_entity, shifter, in_room) in (&*entities, &mut shifters, &mut in_rooms).join() {
+    fn run(&mut self, (entities, mut shifters, mut in_rooms, mut previous_positions): Self::SystemData) {
+        for (entity, shifter, in_room) in (&*entities, &mut shifters, &mut in_rooms).join() {
             if shifter.shifting {
                 if let Some(target_room) = shifter.target_room {
                     shifter.shifting = false;
                     shifter.sensing = false;
                     in_room.room_entity = target_room;
+
+                    // Positions are relative to the room, so the previous one means nothing here
+                    previous_positions.remove(entity);
                 }
             }
         }
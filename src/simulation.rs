@@ -0,0 +1,658 @@
+/// Headless game simulation
+///
+/// Owns the specs `World` and the `PhysicsSystem`, and can be advanced without a window or an
+/// OpenGL context. The windowed `Game` is a thin shell that forwards events to it and draws its
+/// world.
+
+use std::collections::HashMap;
+use specs::prelude::{World, RunNow, Dispatcher, DispatcherBuilder};
+use specs::saveload::U64MarkerAllocator;
+
+use UpdateDeltaTime;
+use error::{Error, GameError};
+use draw::Interpolation;
+use replay::{ReplayRecorder, ReplayPlayer, ReplayTick};
+use input::{self, InputEvents, InputEvent};
+use action::{Action, ActionMap};
+use physics::{self, PhysicsSystem};
+use draw;
+use control;
+use shift;
+use edit;
+use prefab::Prefabs;
+use animate;
+use saveload::{self, SaveLoadErrors, SaveSlots, SlotRequest, SaveScope, CurrentLevel};
+
+
+/// Duration of a single simulation tick, in seconds. All gameplay systems and the physics worlds
+/// are always advanced by exactly this much, regardless of frame rate.
+pub const TICK_DURATION: f64 = 1.0 / 120.0;
+
+/// Upper bound on the time simulated by a single `update`; after a long stall the simulation slows
+/// down instead of trying to catch up with an ever-increasing number of ticks.
+const MAX_FRAME_TIME: f64 = 0.25;
+
+/// Periodic saving of the world while the game runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Autosave {
+    pub file_name: String,
+    /// Number of ticks between saves
+    pub interval: u64,
+    pub backups: usize,
+}
+
+pub struct Simulation {
+    specs_world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    physics_dispatcher: Dispatcher<'static, 'static>,
+    accumulator: f64,
+    recorder: Option<ReplayRecorder>,
+    replay: Option<ReplayPlayer>,
+    /// Contents of the quicksave slots saved during the replay, which must neither read nor
+    /// overwrite the slot files on disk
+    replay_slots: HashMap<u8, String>,
+    autosave: Option<Autosave>,
+    ticks_since_autosave: u64,
+    /// Contents of the world at the last autosave, to skip autosaves while nothing changes
+    last_autosave: Option<String>,
+}
+
+/// Build the part of the per-tick update pipeline that runs before physics.
+///
+/// Systems declare the systems they must run after; anything left unordered is free to run in
+/// parallel. The world is maintained before the physics pipeline runs, so that the physics system
+/// sees the entities created this tick.
+fn build_dispatcher() -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with(draw::StorePreviousPositions, "store_previous_positions", &[])
+
+        .with(input::InputEventsToState, "input_events_to_state", &[])
+        .with(input::MouseInsideRoom, "mouse_inside_room", &["input_events_to_state"])
+        .with(input::PlayerControllerInput, "player_controller_input", &["input_events_to_state"])
+        .with(input::EditorControllerInput, "editor_controller_input", &["mouse_inside_room"])
+        .with(input::AimObjects, "aim_objects", &["input_events_to_state"])
+        .with(input::CameraEdgePan, "camera_edge_pan", &["input_events_to_state"])
+        .with(input::SaveSlotInput, "save_slot_input", &["input_events_to_state"])
+        // Reports presses that nobody consumed, so it must come after every input system
+        .with(input::GlobalInput, "global_input", &[
+            "player_controller_input", "editor_controller_input", "aim_objects", "camera_edge_pan",
+            "save_slot_input",
+        ])
+
+        .with(shift::TrackShiftTarget, "track_shift_target", &[])
+        // Forgets the previous position of objects that change room, after it was stored
+        .with(shift::PhaseShift, "phase_shift", &["track_shift_target", "store_previous_positions"])
+        .with(control::ControlObjects, "control_objects", &["player_controller_input"])
+        .with(edit::ApplyEditEvents, "apply_edit_events", &["editor_controller_input"])
+        .build()
+}
+
+/// Build the part of the per-tick update pipeline that runs the physics system and the systems
+/// that use its results, declared the same way as the systems before physics.
+fn build_physics_dispatcher() -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with(PhysicsSystem::new(), "physics", &[])
+
+        .with(animate::UpdateAnimations, "update_animations", &[])
+        .with(control::UpdateCooldowns, "update_cooldowns", &["physics"])
+        // Needs the aim targets computed by the physics system
+        .with(control::FireHook, "fire_hook", &["physics", "update_cooldowns"])
+        .with(shift::StartPhaseShift, "start_phase_shift", &["physics", "fire_hook"])
+        .with(draw::SetCameraTarget, "set_camera_target", &["physics"])
+        .with(draw::UpdateCamera, "update_camera", &["set_camera_target"])
+
+        // Deletes entities right away, so the systems that read the destroyed ones come first
+        // FIXME: Obsolete, remove the component and system
+        .with(saveload::DestroyEntities, "destroy_entities", &[
+            "physics", "update_animations", "update_cooldowns", "fire_hook", "start_phase_shift",
+            "set_camera_target",
+        ])
+        .build()
+}
+
+impl Simulation {
+    /// Create an empty simulation with all components and resources registered.
+    pub fn new() -> Self {
+        let mut world = World::new();
+
+        saveload::register_components(&mut world);
+        world.register::<saveload::DestroyEntity>();
+
+        world.add_resource(UpdateDeltaTime { dt: TICK_DURATION });
+        world.add_resource(Interpolation { alpha: 1.0 });
+        world.add_resource(input::InputEvents::new());
+        world.add_resource(input::InputState::new());
+        world.add_resource(ActionMap::default());
+        world.add_resource(Prefabs::default());
+        world.add_resource(edit::EditorController::new());
+        world.add_resource(edit::EditHistory::default());
+        world.add_resource(edit::Clipboard::default());
+        world.add_resource(draw::Camera::new());
+        world.add_resource(draw::Screen::default());
+        world.add_resource(SaveSlots::new());
+
+        Simulation {
+            specs_world: world,
+            dispatcher: build_dispatcher(),
+            physics_dispatcher: build_physics_dispatcher(),
+            accumulator: 0.0,
+            recorder: None,
+            replay: None,
+            replay_slots: HashMap::new(),
+            autosave: None,
+            ticks_since_autosave: 0,
+            last_autosave: None,
+        }
+    }
+
+    pub fn specs_world(&self) -> &World {
+        &self.specs_world
+    }
+
+    pub fn specs_world_mut(&mut self) -> &mut World {
+        &mut self.specs_world
+    }
+
+    pub fn action_map(&self) -> ActionMap {
+        self.specs_world.read_resource::<ActionMap>().clone()
+    }
+
+    /// Replace the key bindings used by the input systems; takes effect on the next tick.
+    pub fn set_action_map(&mut self, action_map: ActionMap) {
+        *self.specs_world.write_resource::<ActionMap>() = action_map;
+    }
+
+    /// Replace the prefabs that new objects are spawned from.
+    pub fn set_prefabs(&mut self, prefabs: Prefabs) {
+        *self.specs_world.write_resource::<Prefabs>() = prefabs;
+    }
+
+    /// Take the errors reported by the save and load systems that ran since the last call.
+    fn take_save_load_result(&mut self) -> Result<(), Error> {
+        self.specs_world.write_resource::<SaveLoadErrors>().take_result()
+    }
+
+    /// Load the world from `file_name`, or from `default_storage` if it does not exist.
+    ///
+    /// If the file cannot be read or is corrupt, nothing is added to the world.
+    pub fn load(&mut self, file_name: &str, default_storage: &str) -> Result<(), Error> {
+        saveload::LoadWorld {
+            file_name: file_name.into(),
+            default_storage: default_storage.into(),
+        }.run_now(&mut self.specs_world.res);
+
+        self.take_save_load_result()
+    }
+
+    /// Start a new session from the level in `file_name`: load its rooms and terrain, and spawn
+    /// the objects of its spawn points. A level that does not exist yet starts out empty, and is
+    /// created when the session is first saved.
+    pub fn start_level(&mut self, file_name: &str) -> Result<(), Error> {
+        let exists = ::std::path::Path::new(file_name).exists();
+
+        if exists {
+            self.load(file_name, file_name)?;
+        }
+
+        *self.specs_world.write_resource::<CurrentLevel>() = CurrentLevel {
+            file_name: Some(file_name.into()),
+            modified: !exists,
+        };
+
+        edit::SpawnObjects.run_now(&self.specs_world.res);
+        self.specs_world.maintain();
+
+        Ok(())
+    }
+
+    /// Save the world to `file_name`, keeping `backups` of its previous versions.
+    ///
+    /// If the session was started from a level, only the dynamic state goes to `file_name`, and
+    /// the level file is rewritten only if the level was edited.
+    pub fn save(&mut self, file_name: &str, backups: usize) -> Result<(), Error> {
+        self.specs_world.maintain();
+
+        let current_level = self.specs_world.read_resource::<CurrentLevel>().clone();
+
+        let scope = match current_level.file_name {
+            Some(level) => {
+                if current_level.modified {
+                    saveload::SaveWorld { file_name: level.clone(), backups, scope: SaveScope::Level }
+                        .run_now(&self.specs_world.res);
+
+                    self.take_save_load_result()?;
+                    self.specs_world.write_resource::<CurrentLevel>().modified = false;
+                }
+
+                SaveScope::Session { level }
+            },
+            None => SaveScope::Everything,
+        };
+
+        saveload::SaveWorld { file_name: file_name.into(), backups, scope }
+            .run_now(&self.specs_world.res);
+
+        self.take_save_load_result()
+    }
+
+    pub fn save_to_string(&mut self) -> Result<String, Error> {
+        self.specs_world.maintain();
+        let mut save_world = saveload::SaveWorldToString::default();
+        save_world.run_now(&self.specs_world.res);
+
+        self.take_save_load_result()?;
+        Ok(save_world.contents)
+    }
+
+    pub fn load_from_str(&mut self, contents: &str) -> Result<(), Error> {
+        self.load_from_str_of(contents, "<memory>")
+    }
+
+    /// Load `contents` read from `file_name`, so that a level it refers to is found next to it.
+    fn load_from_str_of(&mut self, contents: &str, file_name: &str) -> Result<(), Error> {
+        saveload::LoadWorldFromString { contents: contents.into(), file_name: file_name.into() }
+            .run_now(&mut self.specs_world.res);
+
+        self.take_save_load_result()
+    }
+
+    /// Delete every entity, including the ones the physics system tracks.
+    fn clear_world(&mut self) {
+        self.specs_world.delete_all();
+        self.specs_world.maintain();
+
+        // Forget the markers of the deleted entities, so that loading re-creates them
+        self.specs_world.add_resource(U64MarkerAllocator::new());
+        self.specs_world.add_resource(edit::EditHistory::default());
+    }
+
+    /// Replace the current world with the one saved in `file_name`.
+    ///
+    /// If the file cannot be loaded, the current world is restored.
+    pub fn quickload(&mut self, file_name: &str) -> Result<(), Error> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut contents = String::new();
+        File::open(file_name)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|error| GameError::LoadError {
+                file_name: file_name.into(),
+                cause: error.to_string(),
+            })?;
+
+        self.quickload_from_str(&contents, file_name)
+    }
+
+    /// Replace the current world with the one saved in `contents`, read from `file_name`,
+    /// restoring the current world if it cannot be loaded.
+    fn quickload_from_str(&mut self, contents: &str, file_name: &str) -> Result<(), Error> {
+        let backup = self.save_to_string()?;
+        let current_level = self.specs_world.read_resource::<CurrentLevel>().clone();
+        self.clear_world();
+
+        if let Err(error) = self.load_from_str_of(contents, file_name) {
+            self.clear_world();
+            self.load_from_str(&backup)?;
+            *self.specs_world.write_resource::<CurrentLevel>() = current_level;
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    fn handle_slot_request(&mut self) {
+        let request = self.specs_world.write_resource::<SaveSlots>().request.take();
+
+        let (result, action, slot) = match request {
+            Some(SlotRequest::Save(slot)) if self.replay.is_some() =>
+                (self.save_replay_slot(slot), "Saved to", slot),
+            Some(SlotRequest::Load(slot)) if self.replay.is_some() =>
+                (self.load_replay_slot(slot), "Loaded", slot),
+            Some(SlotRequest::Save(slot)) =>
+                (self.save(&SaveSlots::file_name(slot), 0), "Saved to", slot),
+            Some(SlotRequest::Load(slot)) =>
+                (self.quickload(&SaveSlots::file_name(slot)), "Loaded", slot),
+            None => return,
+        };
+
+        match result {
+            Ok(()) => println!("{} save slot {}.", action, slot),
+            Err(error) => eprintln!("Error: {}", error),
+        }
+    }
+
+    fn save_replay_slot(&mut self, slot: u8) -> Result<(), Error> {
+        let contents = self.save_to_string()?;
+        self.replay_slots.insert(slot, contents);
+
+        Ok(())
+    }
+
+    fn load_replay_slot(&mut self, slot: u8) -> Result<(), Error> {
+        let contents = match self.replay_slots.get(&slot) {
+            Some(contents) => contents.clone(),
+            None => return Err(format_err!("Save slot {} is empty", slot)),
+        };
+
+        // Written by `save_to_string`, so a level path in it is relative to the current directory
+        self.quickload_from_str(&contents, "<memory>")
+    }
+
+    pub fn set_autosave(&mut self, autosave: Option<Autosave>) {
+        self.autosave = autosave;
+        self.ticks_since_autosave = 0;
+        self.last_autosave = None;
+    }
+
+    fn autosave_tick(&mut self) {
+        let autosave = match self.autosave {
+            Some(ref autosave) => autosave.clone(),
+            None => return,
+        };
+
+        self.ticks_since_autosave += 1;
+
+        if self.ticks_since_autosave < autosave.interval {
+            return;
+        }
+
+        self.ticks_since_autosave = 0;
+
+        // An unchanged world is not saved again, so that it does not push older backups out
+        let contents = match self.save_to_string() {
+            Ok(contents) => contents,
+            Err(error) => {
+                eprintln!("Error: autosave failed: {}", error);
+                return;
+            },
+        };
+
+        if self.last_autosave.as_ref() == Some(&contents) {
+            return;
+        }
+
+        match self.save(&autosave.file_name, autosave.backups) {
+            Ok(()) => self.last_autosave = Some(contents),
+            Err(error) => eprintln!("Error: autosave failed: {}", error),
+        }
+    }
+
+    /// Record the input consumed by every tick into a replay file.
+    ///
+    /// The current world is stored at the start of the replay, so recording should start right
+    /// after the world is loaded.
+    pub fn start_recording(&mut self, file_name: &str) -> Result<(), Error> {
+        let initial_world = self.save_to_string()?;
+        let prefabs = self.specs_world.read_resource::<Prefabs>().to_ron_string()?;
+        self.recorder = Some(ReplayRecorder::create(file_name, initial_world, self.action_map(), prefabs)?);
+
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), Error> {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the world and prefabs a replay started from, and feed the replay's input to every tick instead of
+    /// live input until it runs out. Must be called on a freshly created simulation.
+    ///
+    /// Quicksave slots are kept in memory while the replay runs, starting out empty.
+    pub fn start_replay(&mut self, file_name: &str) -> Result<(), Error> {
+        let replay = ReplayPlayer::open(file_name)?;
+
+        self.set_prefabs(Prefabs::from_ron_str(replay.prefabs())?);
+        self.load_from_str(replay.initial_world())?;
+        self.set_action_map(replay.bindings().clone());
+        self.accumulator = 0.0;
+        // The recorded session's slots started out as whatever was on its disk, which is unknown
+        self.replay_slots.clear();
+        self.replay = Some(replay);
+
+        Ok(())
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Queue an input event; it will be processed on the next tick.
+    pub fn push_input_event(&mut self, input_event: InputEvent) {
+        self.specs_world.write_resource::<InputEvents>().events
+            .push_back(input_event);
+    }
+
+    /// Advance the simulation by `dt` seconds of real time.
+    ///
+    /// Runs as many fixed ticks as fit in the accumulated time, and leaves the remainder for the
+    /// next call; the draw systems use that remainder to interpolate between the last two ticks.
+    pub fn update(&mut self, dt: f64) {
+        self.accumulator += dt.min(MAX_FRAME_TIME);
+
+        while self.accumulator >= TICK_DURATION {
+            self.tick();
+            self.accumulator -= TICK_DURATION;
+        }
+
+        self.specs_world.write_resource::<Interpolation>().alpha = self.accumulator / TICK_DURATION;
+    }
+
+    /// Advance the simulation by exactly one tick of `TICK_DURATION` seconds.
+    pub fn tick(&mut self) {
+        let replayed_tick = match self.replay {
+            Some(ref mut replay) => replay.next_tick(),
+            None => None,
+        };
+
+        if let Some(replayed_tick) = replayed_tick {
+            // Live input is dropped while a replay is running
+            let mut input_events = self.specs_world.write_resource::<InputEvents>();
+            input_events.events.clear();
+            input_events.events.extend(replayed_tick.events);
+
+            self.specs_world.write_resource::<UpdateDeltaTime>().dt = replayed_tick.dt;
+        } else if self.replay.is_some() {
+            println!("Replay finished.");
+            self.replay = None;
+        }
+
+        let recording_error = if let Some(ref mut recorder) = self.recorder {
+            let tick = ReplayTick {
+                dt: self.specs_world.read_resource::<UpdateDeltaTime>().dt,
+                events: self.specs_world.read_resource::<InputEvents>().events
+                    .iter().cloned().collect(),
+            };
+
+            recorder.record_tick(&tick).err()
+        } else {
+            None
+        };
+
+        if let Some(error) = recording_error {
+            eprintln!("Error: {}; recording stopped.", error);
+            self.recorder = None;
+        }
+
+        // FIXME: Move to edit.rs
+        let reset_world = {
+            let action_map = self.specs_world.read_resource::<ActionMap>();
+
+            self.specs_world.read_resource::<InputEvents>().events.iter()
+                .any(|event| match *event {
+                    InputEvent::PressEvent(button) => action_map.is_bound(Action::ResetWorld, &button),
+                    _ => false,
+                })
+        };
+
+        if reset_world {
+            saveload::ResetWorld.run_now(&mut self.specs_world.res);
+            self.specs_world.maintain();
+            self.specs_world.add_resource(edit::EditHistory::default());
+        }
+
+        self.dispatcher.dispatch(&mut self.specs_world.res);
+        edit::handle_clipboard_request(&mut self.specs_world);
+
+        // Edits are applied by maintain, so the history saves their entities on both sides of it
+        edit::begin_command(&self.specs_world);
+        self.specs_world.maintain();
+        edit::finish_command(&self.specs_world);
+        edit::handle_history_request(&mut self.specs_world);
+
+        self.physics_dispatcher.dispatch(&mut self.specs_world.res);
+        self.specs_world.maintain();
+
+        self.handle_slot_request();
+        self.autosave_tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use piston::input::{Button, Key};
+    use specs::prelude::{Builder, Join};
+    use specs::saveload::{U64Marker, MarkedBuilder};
+    use super::Simulation;
+    use saveload::CurrentLevel;
+    use input::{InputEvent, PlayerController};
+    use draw::{Position, Size, Shape, ShapeClass};
+    use physics::{Room, InRoom, Velocity, Force, Aim, CollisionSet};
+    use control::Jump;
+
+    /// A 400x200 room with a player dropped in above its floor
+    fn small_level() -> String {
+        let mut simulation = Simulation::new();
+
+        let room = simulation.specs_world_mut().create_entity()
+            .with(Position { x: 0.0, y: 0.0 })
+            .with(Size { width: 400.0, height: 200.0 })
+            .with(Room)
+            .marked::<U64Marker>()
+            .build();
+
+        simulation.specs_world_mut().create_entity()
+            .with(Position { x: 100.0, y: 150.0 })
+            .with(Shape { size: 10.0, class: ShapeClass::Ball })
+            .with(InRoom { room_entity: room })
+            .with(Velocity::default())
+            .with(Force::default())
+            .with(Aim::default())
+            .with(CollisionSet::default())
+            .with(PlayerController::default())
+            .with(Jump::default())
+            .marked::<U64Marker>()
+            .build();
+
+        simulation.save_to_string().unwrap()
+    }
+
+    fn player_position(simulation: &Simulation) -> Position {
+        let world = simulation.specs_world();
+        let player_controllers = world.read_storage::<PlayerController>();
+        let positions = world.read_storage::<Position>();
+
+        let (_player_controller, position) = (&player_controllers, &positions).join().next()
+            .expect("The level has no player");
+
+        *position
+    }
+
+    #[test]
+    fn player_falls_to_the_floor_and_walks_while_a_key_is_held() {
+        let mut simulation = Simulation::new();
+        simulation.load_from_str(&small_level()).unwrap();
+
+        for _ in 0..120 {
+            simulation.tick();
+        }
+
+        let landed = player_position(&simulation);
+        assert!(landed.y > 150.0 && landed.y < 200.0, "player did not land on the floor: {:?}", landed);
+        assert!((landed.x - 100.0).abs() < 1.0, "player moved without input: {:?}", landed);
+
+        simulation.push_input_event(InputEvent::PressEvent(Button::Keyboard(Key::D)));
+
+        for _ in 0..60 {
+            simulation.tick();
+        }
+
+        let walked = player_position(&simulation);
+        assert!(walked.x > landed.x + 10.0, "player did not walk right: {:?} -> {:?}", landed, walked);
+        assert!(walked.y < 200.0, "player fell through the floor: {:?}", walked);
+    }
+
+    #[test]
+    fn replaying_a_recording_reproduces_the_session() {
+        let replay_file = ::std::env::temp_dir()
+            .join(format!("stacked-worlds-test-{}.replay", ::std::process::id()));
+        let replay_file = replay_file.to_str().unwrap();
+
+        let mut recorded = Simulation::new();
+        recorded.load_from_str(&small_level()).unwrap();
+        recorded.start_recording(replay_file).unwrap();
+
+        let input = [
+            (30, InputEvent::PressEvent(Button::Keyboard(Key::D))),
+            (90, InputEvent::PressEvent(Button::Keyboard(Key::Space))),
+            (100, InputEvent::ReleaseEvent(Button::Keyboard(Key::Space))),
+            (150, InputEvent::ReleaseEvent(Button::Keyboard(Key::D))),
+            (160, InputEvent::PressEvent(Button::Keyboard(Key::A))),
+        ];
+
+        for tick in 0..240 {
+            for &(at, input_event) in input.iter() {
+                if at == tick {
+                    recorded.push_input_event(input_event);
+                }
+            }
+
+            recorded.tick();
+        }
+
+        recorded.stop_recording().unwrap();
+
+        let mut replayed = Simulation::new();
+        replayed.start_replay(replay_file).unwrap();
+
+        for _ in 0..240 {
+            replayed.tick();
+        }
+
+        let _ = ::std::fs::remove_file(replay_file);
+
+        assert_eq!(replayed.save_to_string().unwrap(), recorded.save_to_string().unwrap());
+    }
+
+    #[test]
+    fn quickloads_find_the_level_next_to_the_slot_file() {
+        use std::fs;
+        use std::path::Path;
+
+        // Relative paths, since level paths are only stored relative to the session if they are
+        let directory = format!("target/test-quickload-{}", ::std::process::id());
+        let level_file = format!("{}/levels/small.ron", directory);
+        let slot_file = format!("{}/slots/quicksave-1.ron", directory);
+
+        fs::create_dir_all(format!("{}/levels", directory)).unwrap();
+        fs::create_dir_all(format!("{}/slots", directory)).unwrap();
+        fs::write(&level_file, small_level()).unwrap();
+
+        let mut saved = Simulation::new();
+        saved.start_level(&level_file).unwrap();
+        saved.save(&slot_file, 0).unwrap();
+
+        let mut loaded = Simulation::new();
+        let result = loaded.quickload(&slot_file);
+        let _ = fs::remove_dir_all(&directory);
+        result.unwrap();
+
+        let current_level = loaded.specs_world().read_resource::<CurrentLevel>().file_name.clone();
+        assert!(current_level.map_or(false, |level| Path::new(&level).ends_with("levels/small.ron")));
+        assert!((player_position(&loaded).x - 100.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,134 @@
+/// Consistency checks for levels and saves
+///
+/// A file is loaded through the regular `saveload` code into a fresh simulation, and the
+/// resulting world is checked for mistakes that would otherwise only show up while playing.
+
+use specs::prelude::{World, Entity, Join};
+use specs::saveload::U64Marker;
+
+use error::Error;
+use saveload::SaveLoadErrors;
+use simulation::Simulation;
+use draw::{Position, Size};
+use physics::{Room, RoomProperties, InRoom, RevoluteJoint};
+use input::PlayerController;
+use edit::{SpawnPoint, SpawnKind};
+use shift::RoomLink;
+
+/// Load `file_name` and return a description of every problem found in it.
+pub fn validate_level(file_name: &str) -> Result<Vec<String>, Error> {
+    let mut simulation = Simulation::new();
+    simulation.load(file_name, file_name)?;
+
+    Ok(validate_world(simulation.specs_world()))
+}
+
+pub fn validate_world(world: &World) -> Vec<String> {
+    let entities = world.entities();
+    let markers = world.read_storage::<U64Marker>();
+    let positions = world.read_storage::<Position>();
+    let sizes = world.read_storage::<Size>();
+    let rooms = world.read_storage::<Room>();
+    let in_rooms = world.read_storage::<InRoom>();
+    let revolute_joints = world.read_storage::<RevoluteJoint>();
+    let player_controllers = world.read_storage::<PlayerController>();
+    let spawn_points = world.read_storage::<SpawnPoint>();
+    let room_links = world.read_storage::<RoomLink>();
+    let room_properties = world.read_storage::<RoomProperties>();
+
+    let name = |entity: Entity| {
+        let name = match markers.get(entity) {
+            Some(marker) => format!("entity {:?}", marker),
+            None => format!("unsaved entity {}", entity.id()),
+        };
+
+        // Named rooms are easier to find by their name
+        match room_properties.get(entity).filter(|properties| !properties.name.is_empty()) {
+            Some(properties) => format!("{} \"{}\"", name, properties.name),
+            None => name,
+        }
+    };
+
+    // Problems that loading already worked around, but that are still in the file
+    let mut issues = world.read_resource::<SaveLoadErrors>().load_warnings.clone();
+
+    for (entity, in_room) in (&*entities, &in_rooms).join() {
+        let room_entity = in_room.room_entity;
+
+        if !entities.is_alive(room_entity) || rooms.get(room_entity).is_none() {
+            issues.push(format!("{} is in {}, which is not a room", name(entity), name(room_entity)));
+            continue;
+        }
+
+        // Terrain boxes are positioned relative to their room
+        let (position, size) = match (positions.get(entity), sizes.get(entity)) {
+            (Some(position), Some(size)) => (position, size),
+            _ => continue,
+        };
+
+        let room_size = match sizes.get(room_entity) {
+            Some(room_size) => room_size,
+            None => continue,
+        };
+
+        if position.x < 0.0 || position.y < 0.0 ||
+            position.x + size.width > room_size.width || position.y + size.height > room_size.height
+        {
+            issues.push(format!("terrain {} at ({}, {}) with size {}x{} is outside its room {} of size {}x{}",
+                                name(entity), position.x, position.y, size.width, size.height,
+                                name(room_entity), room_size.width, room_size.height));
+        }
+    }
+
+    let room_bounds: Vec<_> = (&*entities, &rooms, &positions, &sizes).join()
+        .map(|(entity, _room, position, size)| (entity, *position, *size))
+        .collect();
+
+    for (index, &(entity, position, size)) in room_bounds.iter().enumerate() {
+        for &(other_entity, other_position, other_size) in &room_bounds[index + 1..] {
+            let overlaps = position.x < other_position.x + other_size.width &&
+                other_position.x < position.x + size.width &&
+                position.y < other_position.y + other_size.height &&
+                other_position.y < position.y + size.height;
+
+            if overlaps {
+                issues.push(format!("room {} overlaps room {}", name(entity), name(other_entity)));
+            }
+        }
+    }
+
+    // Loading rejects references to markers without a record, but the record may have no position
+    for (entity, revolute_joint) in (&*entities, &revolute_joints).join() {
+        let target = revolute_joint.linked_to_entity;
+
+        if !entities.is_alive(target) || positions.get(target).is_none() {
+            issues.push(format!("chain link {} is joined to {}, which does not exist", name(entity), name(target)));
+        }
+    }
+
+    for (entity, room_link) in (&*entities, &room_links).join() {
+        let target = room_link.target_room;
+
+        if rooms.get(entity).is_none() {
+            issues.push(format!("{} has a room link, but is not a room", name(entity)));
+        } else if !entities.is_alive(target) || rooms.get(target).is_none() {
+            issues.push(format!("room {} is linked to {}, which is not a room", name(entity), name(target)));
+        }
+    }
+
+    let player_count = (&player_controllers).join().count();
+
+    if player_count > 1 {
+        issues.push(format!("there are {} player-controlled objects instead of one", player_count));
+    }
+
+    let player_spawn_count = (&spawn_points).join()
+        .filter(|spawn_point| spawn_point.kind == SpawnKind::Player)
+        .count();
+
+    if player_spawn_count > 1 {
+        issues.push(format!("there are {} player spawn points instead of one", player_spawn_count));
+    }
+
+    issues
+}